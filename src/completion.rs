@@ -0,0 +1,379 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use crate::parser;
+use crate::parser::glob::matches;
+use crate::parser::tokens::{tokenize, Tokens};
+use crate::parser::vars::{Context, Variable, WriterOverride};
+
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    /// text replacing the word, quoted like the word
+    pub value: String,
+    /// text shown in the menu
+    pub display: String,
+    /// shown next to the candidate in the menu
+    pub description: Option<String>
+}
+
+/// Completions declared with the complete builtin for the arguments of a command, or of one of its subcommands
+#[derive(Debug, Clone, Default)]
+pub struct CompletionSpec {
+    /// subcommand the spec applies after, None for the arguments before any subcommand
+    pub subcommand: Option<String>,
+    pub words: Vec<String>,
+    /// offered instead of the words when the completed word starts with -
+    pub flags: Vec<String>,
+    /// shown next to the words and flags of the spec
+    pub description: Option<String>,
+    /// function called with the words of the command, each line it prints is another candidate
+    pub function: Option<String>,
+    /// only files matching one of the patterns (and directories) are completed
+    pub patterns: Vec<String>,
+    pub no_files: bool
+}
+
+impl CompletionSpec {
+    /// The complete command declaring the spec
+    pub fn to_command(&self, command: &str) -> String {
+        let quote = |str: &str| if str.is_empty() || str.contains(char::is_whitespace) { format!("\"{}\"", str) } else { str.to_string() };
+        let mut out = format!("complete {}", quote(command));
+        if let Some(subcommand) = &self.subcommand {
+            out += &format!(" -s {}", quote(subcommand));
+        }
+        if !self.words.is_empty() {
+            out += &format!(" -a {}", quote(&self.words.join(" ")));
+        }
+        for flag in &self.flags {
+            out += &format!(" -o {}", quote(flag));
+        }
+        if let Some(description) = &self.description {
+            out += &format!(" -d {}", quote(description));
+        }
+        if let Some(function) = &self.function {
+            out += &format!(" -f {}", quote(function));
+        }
+        for pattern in &self.patterns {
+            out += &format!(" -p {}", quote(pattern));
+        }
+        if self.no_files {
+            out += " -n";
+        }
+        out
+    }
+}
+
+#[derive(Debug)]
+pub struct Completion {
+    /// char position where the completed word starts
+    pub start: usize,
+    pub candidates: Vec<Candidate>
+}
+
+/// Word under the cursor, as written and without quotes
+struct Word {
+    start: usize,
+    raw: String,
+    text: String,
+    /// opening quote of an unterminated quoted word
+    quote: Option<char>
+}
+
+/// Finds the word ending at the cursor. Words are separated by unquoted spaces and operators
+fn find_word(input: &[char], idx: usize) -> Word {
+    let mut start = 0;
+    let mut quote: Option<char> = None;
+    let mut escape = false;
+    for (i, letter) in input[..idx].iter().enumerate() {
+        match letter {
+            _ if escape => escape = false,
+            '\\' if quote != Some('\'') => escape = true,
+            '\'' | '"' if quote.is_none() => quote = Some(*letter),
+            letter if quote == Some(*letter) => quote = None,
+            ' ' | '\t' | '\n' | ';' | '|' | '&' | '(' | ')' | '<' | '>' | '=' if quote.is_none() => start = i + 1,
+            _ => {}
+        }
+    }
+    let raw: String = input[start..idx].iter().collect();
+    let mut text = String::new();
+    let mut escape = false;
+    let mut word_quote: Option<char> = None;
+    for letter in raw.chars() {
+        match letter {
+            _ if escape => {
+                text.push(letter);
+                escape = false;
+            },
+            '\\' if word_quote != Some('\'') => escape = true,
+            '\'' | '"' if word_quote.is_none() => word_quote = Some(letter),
+            letter if word_quote == Some(letter) => word_quote = None,
+            letter => text.push(letter)
+        }
+    }
+    Word { start, raw, text, quote: word_quote }
+}
+
+/// Whether the word at start is a command name, judging by the tokens before it
+fn is_command_position(input: &[char], start: usize) -> bool {
+    let before: String = input[..start].iter().collect();
+    let tokens = match tokenize(&mut before.as_bytes()) {
+        Ok(tokens) => tokens,
+        Err(_) => return false
+    };
+    let last = tokens.iter().rev().find(|token| !matches!(token.token, Tokens::Space));
+    match last {
+        None => true,
+        Some(token) => token.token.starts_command() || matches!(&token.token, Tokens::Literal(str, false) if str == "!")
+    }
+}
+
+/// Words of the command the completed word belongs to, before the word. Variables and substitutions are empty words
+fn command_words(input: &[char], start: usize) -> Vec<String> {
+    let before: String = input[..start].iter().collect();
+    let tokens = match tokenize(&mut before.as_bytes()) {
+        Ok(tokens) => tokens,
+        Err(_) => return Vec::new()
+    };
+    let mut words = Vec::new();
+    let mut current: Option<String> = None;
+    for token in tokens {
+        match token.token {
+            Tokens::Space => words.extend(current.take()),
+            Tokens::Literal(str, _) => current.get_or_insert_with(String::new).push_str(&str),
+            token if token.starts_command() => {
+                words.clear();
+                current = None;
+            },
+            _ => { current.get_or_insert_with(String::new); }
+        }
+    }
+    words.extend(current);
+    // a negated pipeline still completes the command after the !
+    let negations = words.iter().take_while(|word| *word == "!").count();
+    words.drain(..negations);
+    words
+}
+
+/// Escapes characters the tokenizer would treat specially
+fn escape(str: &str) -> String {
+    let mut out = String::new();
+    for letter in str.chars() {
+        if matches!(letter, ' ' | '\t' | '\'' | '"' | '\\' | '$' | '@' | '&' | ';' | '|' | '<' | '>' | '(' | ')' | '[' | ']' | '*' | '?' | '{' | '}' | '#' | '=') {
+            out.push('\\');
+        }
+        out.push(letter);
+    }
+    out
+}
+
+/// Quotes a completed word the way it was started, closing the quote unless more can follow (like after a directory)
+fn quote(word: &Word, value: &str, done: bool) -> String {
+    match word.quote {
+        Some(quote) => {
+            let mut out = format!("{}{}", quote, value.replace(quote, &format!("\\{}", quote)));
+            if done { out.push(quote); }
+            out
+        },
+        None => escape(value)
+    }
+}
+
+fn get_home(ctx: &Context) -> Option<String> {
+    match ctx.exports.get("HOME") {
+        Some(home) => Some(home.to_string()),
+        None => std::env::var("HOME").ok()
+    }
+}
+
+/// Files in the directory of the word starting with its last component, matching one of the patterns if there are any.
+/// Directories end with /
+fn complete_paths(ctx: &Context, word: &Word, executables_only: bool, patterns: &[String]) -> Vec<Candidate> {
+    let (dir, prefix) = match word.text.rfind('/') {
+        Some(i) => (&word.text[..i + 1], &word.text[i + 1..]),
+        None => ("", word.text.as_str())
+    };
+    let dir_path = match dir.strip_prefix('~') {
+        Some(rest) if rest.starts_with('/') => match get_home(ctx) {
+            Some(home) => format!("{}{}", home, rest),
+            None => return Vec::new()
+        },
+        _ if dir.is_empty() => String::from("."),
+        _ => dir.to_string()
+    };
+    let entries = match fs::read_dir(&dir_path) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new()
+    };
+    let mut candidates = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) { continue }
+        let path = entry.path();
+        let is_dir = path.is_dir();
+        if executables_only && !is_dir && !is_executable(&path) { continue }
+        let chars: Vec<char> = name.chars().collect();
+        if !is_dir && !patterns.is_empty() && !patterns.iter().any(|pattern| matches(&pattern.chars().collect::<Vec<char>>(), &chars)) {
+            continue
+        }
+        let display = if is_dir { format!("{}/", name) } else { name.clone() };
+        // the ~ stays as written, it's expanded by the tokenizer
+        let value = match dir.strip_prefix('~') {
+            Some(rest) => format!("~{}", quote(word, &format!("{}{}", rest, display), !is_dir)),
+            None => quote(word, &format!("{}{}", dir, display), !is_dir)
+        };
+        candidates.push(Candidate { value, display, description: None });
+    }
+    candidates.sort_by(|a, b| a.display.cmp(&b.display));
+    candidates
+}
+
+pub fn is_executable(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+/// Builtins and user defined functions with their description, and executables in $PATH
+fn complete_commands(ctx: &Context, word: &Word) -> Vec<Candidate> {
+    let mut names = BTreeMap::new();
+    // names starting with $ or @ are value functions like $trim()
+    for func in ctx.native_func.values().filter(|func| !func.name.starts_with(['$', '@'])) {
+        names.insert(func.name.clone(), Some(func.description.clone()));
+    }
+    for scope in &ctx.scopes {
+        names.extend(scope.func.values().map(|func| (func.name.clone(), func.description.clone())));
+    }
+    let path = match ctx.exports.get("PATH") {
+        Some(path) => path.to_string(),
+        None => std::env::var("PATH").unwrap_or_default()
+    };
+    for dir in path.split(':').filter(|dir| !dir.is_empty()) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => continue
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with(&word.text) && !names.contains_key(&name) && is_executable(&entry.path()) {
+                names.insert(name, None);
+            }
+        }
+    }
+    names.into_iter()
+        .filter(|(name, _)| name.starts_with(&word.text))
+        .map(|(name, description)| Candidate { value: quote(word, &name, true), display: name, description })
+        .collect()
+}
+
+/// Variables in all scopes and exported ones as env::NAME, completed with the $ or @ the word starts with
+fn complete_variables(ctx: &Context, word: &Word) -> Vec<Candidate> {
+    let sigil = &word.raw[..1];
+    let prefix = word.raw[1..].trim_start_matches('{');
+    let mut names = std::collections::BTreeSet::new();
+    for scope in &ctx.scopes {
+        names.extend(scope.vars.keys().cloned());
+    }
+    names.extend(ctx.exports.keys().map(|name| format!("env::{}", name)));
+    let braces = word.raw[1..].starts_with('{');
+    names.into_iter()
+        .filter(|name| name.starts_with(prefix) && !name.is_empty())
+        .map(|name| Candidate {
+            value: if braces { format!("{}{{{}}}", sigil, name) } else { format!("{}{}", sigil, name) },
+            display: format!("{}{}", sigil, name),
+            description: None
+        })
+        .collect()
+}
+
+/// Candidates of the specs declared for the command. The first word after the command not starting with - selects the
+/// specs of that subcommand. None if nothing was declared for it
+fn complete_arguments(ctx: &mut Context, words: &[String], word: &Word) -> Option<Vec<Candidate>> {
+    let subcommand = words[1..].iter().find(|word| !word.starts_with('-'));
+    let specs: Vec<CompletionSpec> = ctx.completions.get(&words[0])?.iter()
+        .filter(|spec| spec.subcommand.as_ref() == subcommand)
+        .cloned()
+        .collect();
+    if specs.is_empty() {
+        return None;
+    }
+    let mut candidates = Vec::new();
+    let mut has_words = false;
+    for spec in &specs {
+        let mut names = if word.text.starts_with('-') { spec.flags.clone() } else { spec.words.clone() };
+        if let Some(function) = &spec.function {
+            let mut args: Vec<Variable> = words.iter().map(|word| Variable::String(word.clone())).collect();
+            args.push(Variable::String(word.text.clone()));
+            // errors can't be shown while editing, the function just doesn't add anything
+            let depth = ctx.scopes.len();
+            ctx.add_scope();
+            if let Ok(null) = File::options().write(true).open("/dev/null") {
+                ctx.scopes.last_mut().unwrap().stderr_override = Some(WriterOverride::File(null));
+            }
+            let output = parser::capture(function, args, ctx);
+            ctx.restore_scopes(depth);
+            if let Ok(output) = output {
+                names.extend(output.lines().filter(|line| !line.is_empty()).map(String::from));
+            }
+        }
+        has_words |= !names.is_empty();
+        candidates.extend(names.into_iter().filter(|name| name.starts_with(&word.text)).map(|name| Candidate {
+            value: quote(word, &name, true),
+            display: name,
+            description: spec.description.clone()
+        }));
+    }
+    let patterns: Vec<String> = specs.iter().flat_map(|spec| spec.patterns.clone()).collect();
+    let no_files = specs.iter().any(|spec| spec.no_files);
+    if !no_files && !word.text.starts_with('-') && (!patterns.is_empty() || !has_words) {
+        candidates.extend(complete_paths(ctx, word, false, &patterns));
+    }
+    Some(candidates)
+}
+
+/// Candidates for an argument of a builtin, by the name of the argument: directories for dir, job numbers for job and
+/// commands for command. None for other arguments, which are files
+fn complete_builtin_arguments(ctx: &Context, words: &[String], word: &Word) -> Option<Vec<Candidate>> {
+    let func = ctx.native_func.get(&words[0])?;
+    // the last argument takes the remaining words
+    let arg = func.args.get(words.len() - 1).or(func.args.last())?;
+    Some(match arg.as_str() {
+        "dir" => complete_paths(ctx, word, false, &[]).into_iter().filter(|candidate| candidate.display.ends_with('/')).collect(),
+        "job" | "jobs" => ctx.jobs.iter()
+            .map(|job| (format!("%{}", job.id), job))
+            .filter(|(id, _)| id.starts_with(&word.text))
+            .map(|(id, job)| Candidate { value: id.clone(), display: id, description: Some(job.command.clone()) })
+            .collect(),
+        "command" => complete_commands(ctx, word),
+        _ => return None
+    })
+}
+
+/// Completions for the word before the cursor (idx, in chars)
+pub fn complete(ctx: &mut Context, input: &str, idx: usize) -> Completion {
+    let chars: Vec<char> = input.chars().collect();
+    let word = find_word(&chars, idx);
+    let candidates = if (word.raw.starts_with('$') || word.raw.starts_with('@')) && !word.raw.contains('/') {
+        complete_variables(ctx, &word)
+    } else if is_command_position(&chars, word.start) {
+        if word.text.contains('/') { complete_paths(ctx, &word, true, &[]) } else { complete_commands(ctx, &word) }
+    } else {
+        let words = command_words(&chars, word.start);
+        let declared = if words.is_empty() { None } else { complete_arguments(ctx, &words, &word) };
+        declared
+            .or_else(|| if words.is_empty() { None } else { complete_builtin_arguments(ctx, &words, &word) })
+            .unwrap_or_else(|| complete_paths(ctx, &word, false, &[]))
+    };
+    Completion { start: word.start, candidates }
+}
+
+/// Longest prefix shared by all candidate values
+pub fn common_prefix(candidates: &[Candidate]) -> String {
+    let mut prefix: Vec<char> = match candidates.first() {
+        Some(first) => first.value.chars().collect(),
+        None => return String::new()
+    };
+    for candidate in &candidates[1..] {
+        let len = prefix.iter().zip(candidate.value.chars()).take_while(|(a, b)| **a == *b).count();
+        prefix.truncate(len);
+    }
+    prefix.into_iter().collect()
+}
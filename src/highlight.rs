@@ -0,0 +1,238 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use os_pipe::PipeReader;
+use termion::color;
+use crate::completion::is_executable;
+use crate::parser::tokens::{tokenize, Tokens};
+use crate::parser::vars::Context;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Style {
+    Plain,
+    Keyword,
+    Variable,
+    String,
+    Operator,
+    Redirect,
+    Comment,
+    /// a command that's neither a function nor an executable
+    Error
+}
+
+impl Style {
+    fn color(self) -> String {
+        match self {
+            Style::Plain => color::Fg(color::Reset).to_string(),
+            Style::Keyword => color::Fg(color::Magenta).to_string(),
+            Style::Variable => color::Fg(color::Cyan).to_string(),
+            Style::String => color::Fg(color::Yellow).to_string(),
+            Style::Operator => color::Fg(color::Blue).to_string(),
+            Style::Redirect => color::Fg(color::Green).to_string(),
+            Style::Comment => color::Fg(color::LightBlack).to_string(),
+            Style::Error => color::Fg(color::Red).to_string()
+        }
+    }
+
+    /// Literals and spaces are None, strings are coloured by their quotes
+    fn of(token: &Tokens) -> Option<Style> {
+        Some(match token {
+            Tokens::Space | Tokens::Literal(..) => return None,
+            Tokens::If | Tokens::Else | Tokens::While | Tokens::For | Tokens::Function | Tokens::End | Tokens::Let
+                | Tokens::Break | Tokens::Continue | Tokens::Return => Style::Keyword,
+            Tokens::StringVariable(..) | Tokens::ArrayVariable(..) | Tokens::StringFunction(_) | Tokens::ArrayFunction(_)
+                | Tokens::ParameterExpansion(_) | Tokens::Tilde(_) | Tokens::Math(_) => Style::Variable,
+            Tokens::FileRead | Tokens::FileWrite { .. } | Tokens::FileWriteAll { .. } | Tokens::FileDuplicate { .. }
+                | Tokens::HereDoc { .. } | Tokens::HereString => Style::Redirect,
+            _ => Style::Operator
+        })
+    }
+}
+
+type Commands = Arc<Mutex<HashMap<String, Option<bool>>>>;
+
+/// Colours the input of the line editor. Commands are looked up on $PATH in a background thread so a slow filesystem
+/// doesn't block typing, a command is only shown as unknown once its lookup finished
+#[derive(Debug, Default)]
+pub struct Highlighter {
+    /// builtins and user defined functions
+    functions: HashSet<String>,
+    path: String,
+    /// whether a command was found, None while it's looked up
+    commands: Commands,
+    /// names to look up with the $PATH and the commands to store the result in, started by refresh
+    lookups: Option<Sender<(String, String, Commands)>>,
+    /// readable once a lookup finished, so the editor draws the input again
+    wake: Option<PipeReader>
+}
+
+impl Highlighter {
+    /// Takes the functions and $PATH of the shell. Lookups are redone, as commands may have been installed since
+    pub fn refresh(&mut self, ctx: &Context) {
+        self.functions = ctx.native_func.keys().filter(|name| !name.starts_with(['$', '@'])).cloned().collect();
+        for scope in &ctx.scopes {
+            self.functions.extend(scope.func.keys().cloned());
+        }
+        self.path = match ctx.exports.get("PATH") {
+            Some(path) => path.to_string(),
+            None => std::env::var("PATH").unwrap_or_default()
+        };
+        self.commands = Arc::default();
+        if self.lookups.is_none() {
+            self.start();
+        }
+    }
+
+    /// Starts the thread doing the lookups one after another
+    fn start(&mut self) {
+        let (reader, mut writer) = match os_pipe::pipe() {
+            Ok(pipe) => pipe,
+            Err(_) => return
+        };
+        // nobody reads the pipe while a command runs, the lookup thread mustn't block on it
+        unsafe { libc::fcntl(writer.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+        let (sender, receiver) = mpsc::channel::<(String, String, Commands)>();
+        thread::spawn(move || {
+            for (name, path, commands) in receiver {
+                let found = if name.contains('/') {
+                    is_executable(Path::new(&name))
+                } else {
+                    path.split(':').filter(|dir| !dir.is_empty()).any(|dir| is_executable(&Path::new(dir).join(&name)))
+                };
+                if let Ok(mut commands) = commands.lock() {
+                    commands.insert(name, Some(found));
+                }
+                let _ = writer.write(&[0]);
+            }
+        });
+        self.lookups = Some(sender);
+        self.wake = Some(reader);
+    }
+
+    /// Readable once a lookup finished and the input should be highlighted again
+    pub fn wake(&self) -> Option<&PipeReader> {
+        self.wake.as_ref()
+    }
+
+    /// Whether a command exists, None until the lookup queued by the first call finished
+    pub fn lookup(&self, name: &str) -> Option<bool> {
+        if self.functions.contains(name) {
+            return Some(true);
+        }
+        let mut commands = self.commands.lock().ok()?;
+        if let Some(found) = commands.get(name) {
+            return *found;
+        }
+        let lookups = self.lookups.as_ref()?;
+        if lookups.send((name.to_string(), self.path.clone(), Arc::clone(&self.commands))).is_ok() {
+            commands.insert(name.to_string(), None);
+        }
+        None
+    }
+
+    /// The input with terminal colours. Input the tokenizer can't read yet, like an unterminated ${, only has its
+    /// strings and comments coloured
+    pub fn highlight(&self, input: &str) -> String {
+        let chars: Vec<char> = input.chars().collect();
+        let mut styles = quote_styles(&chars);
+        if let Ok(mut tokens) = tokenize(&mut input.as_bytes()) {
+            tokens.sort_by_key(|token| token.start);
+            let mut command_next = true;
+            for (i, token) in tokens.iter().enumerate() {
+                let mut start = token.start;
+                let mut len = token.token.to_str().chars().count();
+                let style = match &token.token {
+                    Tokens::Space => None,
+                    Tokens::Literal(name, false) if name == "!" => None,
+                    Tokens::Literal(name, quoted) => {
+                        let command = command_next && !quoted;
+                        command_next = false;
+                        // a command name continued by a variable or quotes can't be looked up
+                        let whole = tokens.get(i + 1).is_none_or(|next| matches!(next.token, Tokens::Space) || next.token.starts_command());
+                        if command && whole && self.lookup(name) == Some(false) { Some(Style::Error) } else { None }
+                    },
+                    token => {
+                        command_next = token.starts_command();
+                        match token {
+                            // the ( isn't part of the name
+                            Tokens::StringFunction(_) | Tokens::ArrayFunction(_) => len += 1,
+                            // the fd is written before the >, but the token starts at it
+                            Tokens::FileWrite { fd, .. } | Tokens::FileDuplicate { fd, .. } if *fd != 1 && start > 0 => {
+                                start -= 1;
+                            },
+                            _ => {}
+                        }
+                        Style::of(token)
+                    }
+                };
+                if let Some(style) = style {
+                    for letter_style in styles.iter_mut().take(start + len).skip(start) {
+                        *letter_style = style;
+                    }
+                }
+            }
+        }
+        let mut out = String::new();
+        let mut current = Style::Plain;
+        for (letter, style) in chars.into_iter().zip(styles) {
+            // the continuation prompt printed after a newline keeps its own colours
+            let style = if letter == '\n' { Style::Plain } else { style };
+            if style != current {
+                out += &style.color();
+                current = style;
+            }
+            out.push(letter);
+        }
+        if current != Style::Plain {
+            out += &Style::Plain.color();
+        }
+        out
+    }
+}
+
+/// Styles of quoted strings and comments, which the tokenizer doesn't keep the positions of
+fn quote_styles(chars: &[char]) -> Vec<Style> {
+    let mut styles = vec![Style::Plain; chars.len()];
+    let mut quote: Option<char> = None;
+    let mut escape = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let letter = chars[i];
+        match letter {
+            _ if escape => escape = false,
+            '\\' if quote != Some('\'') => escape = true,
+            '\'' | '"' if quote.is_none() => quote = Some(letter),
+            letter if quote == Some(letter) => {
+                styles[i] = Style::String;
+                quote = None;
+                i += 1;
+                continue;
+            },
+            // # in ${#name} isn't a comment
+            '$' if quote != Some('\'') && chars.get(i + 1) == Some(&'{') => {
+                while i < chars.len() && chars[i] != '}' {
+                    if quote.is_some() { styles[i] = Style::String; }
+                    i += 1;
+                }
+                continue;
+            },
+            '#' if quote.is_none() => {
+                while i < chars.len() && chars[i] != '\n' {
+                    styles[i] = Style::Comment;
+                    i += 1;
+                }
+                continue;
+            },
+            _ => {}
+        }
+        if quote.is_some() {
+            styles[i] = Style::String;
+        }
+        i += 1;
+    }
+    styles
+}
@@ -0,0 +1,268 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{Result, bail, Context as AnyhowContext};
+use crate::parser::vars::Context;
+
+/// Number of entries kept when HISTSIZE isn't set
+const DEFAULT_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    /// unix time the command was run at
+    pub time: u64,
+    /// number of times the command was run
+    pub count: usize,
+    /// working directory the command was last run in
+    pub dir: Option<String>,
+    pub command: String
+}
+
+/// Reads an escaped field up to an unescaped separator, returning it with the rest of the line
+fn unescape(str: &str, separator: Option<char>) -> (String, Option<&str>) {
+    let mut out = String::new();
+    let mut chars = str.char_indices();
+    while let Some((i, letter)) = chars.next() {
+        match letter {
+            '\\' => match chars.next() {
+                Some((_, 'n')) => out.push('\n'),
+                Some((_, letter)) => out.push(letter),
+                None => {}
+            },
+            letter if Some(letter) == separator => return (out, Some(&str[i + 1..])),
+            letter => out.push(letter)
+        }
+    }
+    (out, None)
+}
+
+fn escape(str: &str) -> String {
+    str.replace('\\', "\\\\").replace('\n', "\\n").replace(';', "\\;")
+}
+
+impl HistoryEntry {
+    /// Entries are stored one per line as time,count,dir;command with newlines, backslashes and semicolons escaped.
+    /// Lines written before the count and directory were kept are time;command
+    fn parse(line: &str) -> Option<HistoryEntry> {
+        let (header, command) = unescape(line, Some(';'));
+        let mut fields = header.splitn(3, ',');
+        let time = fields.next()?.parse().ok()?;
+        let count = fields.next().map(|count| count.parse().ok()).unwrap_or(Some(1))?;
+        let dir = fields.next().map(String::from);
+        Some(HistoryEntry { time, count, dir, command: unescape(command?, None).0 })
+    }
+
+    fn to_line(&self) -> String {
+        let dir = self.dir.as_ref().map(|dir| format!(",{}", escape(dir))).unwrap_or_default();
+        format!("{},{}{};{}\n", self.time, self.count, dir, escape(&self.command))
+    }
+}
+
+/// Lock held while the history file is appended to or rewritten, so sessions don't lose each other's entries. It's a
+/// separate file, as rewriting replaces the history file
+fn lock(path: &Path) -> Result<File> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let lock_path = path.with_extension("lock");
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)
+        .with_context(|| format!("Cannot open history lock {}", lock_path.display()))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        bail!("Cannot lock history file {}: {}", path.display(), std::io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+/// Commands run in the interactive shell. Every session appends to the same file, so entries of sessions running at the
+/// same time are kept
+#[derive(Debug, Default)]
+pub struct History {
+    pub entries: Vec<HistoryEntry>,
+    /// file the history is persisted to, None in scripts
+    path: Option<PathBuf>,
+    pub limit: usize
+}
+
+/// Number of entries to keep, from HISTSIZE as set in the shell or in its environment
+pub fn limit(ctx: &mut Context) -> usize {
+    let size = match ctx.get_var("HISTSIZE") {
+        Some(size) => Some(size.to_string()),
+        None => ctx.exports.get("HISTSIZE").map(|size| size.to_string())
+    };
+    size.and_then(|size| size.parse().ok()).unwrap_or(DEFAULT_LIMIT)
+}
+
+impl History {
+    pub fn new() -> History {
+        History { entries: Vec::new(), path: None, limit: DEFAULT_LIMIT }
+    }
+
+    /// $XDG_DATA_HOME/rush/history, or ~/.local/share/rush/history
+    pub fn default_path() -> Option<PathBuf> {
+        match std::env::var("XDG_DATA_HOME") {
+            Ok(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("rush/history")),
+            _ => std::env::var("HOME").ok().map(|home| PathBuf::from(home).join(".local/share/rush/history"))
+        }
+    }
+
+    /// Loads the history file, dropping older duplicates. The file is rewritten when it grew past the limit
+    pub fn load(path: PathBuf, limit: usize) -> Result<History> {
+        let mut history = History { entries: Vec::new(), path: Some(path.clone()), limit };
+        let _lock = lock(&path)?;
+        let lines = history.read(&path)?;
+        if lines > history.entries.len() {
+            history.write(&path)?;
+        }
+        Ok(history)
+    }
+
+    /// Adds the entries of the history file, returning the number of lines it has
+    fn read(&mut self, path: &Path) -> Result<usize> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err).with_context(|| format!("Cannot read history file {}", path.display()))
+        };
+        for entry in content.lines().filter_map(HistoryEntry::parse) {
+            self.push(entry);
+        }
+        Ok(content.lines().count())
+    }
+
+    /// Replaces the history file with the entries, only while the lock is held
+    fn write(&self, path: &Path) -> Result<()> {
+        let content: String = self.entries.iter().map(|entry| entry.to_line()).collect();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content)
+            .and_then(|_| fs::rename(&tmp, path))
+            .with_context(|| format!("Cannot write history file {}", path.display()))
+    }
+
+    /// Adds an entry in memory, replacing an older entry with the same command. Sessions running at the same time write
+    /// counts from their own entries, so the count of the newer entry only wins if it's higher
+    fn push(&mut self, mut entry: HistoryEntry) {
+        if let Some(idx) = self.entries.iter().position(|old| old.command == entry.command) {
+            entry.count = entry.count.max(self.entries.remove(idx).count + 1);
+        }
+        self.entries.push(entry);
+        if self.entries.len() > self.limit {
+            let extra = self.entries.len() - self.limit;
+            self.entries.drain(..extra);
+        }
+    }
+
+    /// Adds a command and appends it to the history file
+    pub fn add(&mut self, command: &str) -> Result<()> {
+        let command = command.trim_end_matches('\n');
+        if command.trim().is_empty() {
+            return Ok(());
+        }
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+        let count = self.entries.iter().find(|entry| entry.command == command).map(|entry| entry.count + 1).unwrap_or(1);
+        let dir = std::env::current_dir().ok().map(|dir| dir.to_string_lossy().to_string());
+        let entry = HistoryEntry { time, count, dir, command: command.to_string() };
+        if let Some(path) = &self.path {
+            let _lock = lock(path)?;
+            OpenOptions::new().create(true).append(true).open(path)
+                .and_then(|mut file| file.write_all(entry.to_line().as_bytes()))
+                .with_context(|| format!("Cannot write history file {}", path.display()))?;
+        }
+        self.push(entry);
+        Ok(())
+    }
+
+    /// Removes the entries from memory and from the history file. The file is read again first, so the entries other
+    /// sessions added since it was loaded are kept
+    fn remove(&mut self, removed: impl Fn(&HistoryEntry) -> bool) -> Result<()> {
+        self.entries.retain(|entry| !removed(entry));
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(())
+        };
+        let _lock = lock(path)?;
+        let mut file = History { entries: Vec::new(), path: None, limit: self.limit };
+        file.read(path)?;
+        file.entries.retain(|entry| !removed(entry));
+        file.write(path)
+    }
+
+    /// Removes the entry by its index. Commands are only listed once, so it's removed by its command
+    pub fn delete(&mut self, idx: usize) -> Result<()> {
+        let command = self.entries[idx].command.clone();
+        self.remove(|entry| entry.command == command)
+    }
+
+    pub fn clear(&mut self) -> Result<()> {
+        self.remove(|_| true)
+    }
+
+    /// Entry by its number as listed by the history builtin (starting at 1), or counted from the end when negative
+    pub fn get(&self, num: i64) -> Option<&HistoryEntry> {
+        let idx = if num < 0 { self.entries.len() as i64 + num } else { num - 1 };
+        if idx < 0 { return None }
+        self.entries.get(idx as usize)
+    }
+
+    /// Replaces !! with the last command and !n or !-n with the command of that number. Single quoted text isn't expanded
+    pub fn expand(&self, line: &str) -> Result<String> {
+        let chars: Vec<char> = line.chars().collect();
+        let mut out = String::new();
+        let mut quoted = false;
+        let mut i = 0;
+        while i < chars.len() {
+            let letter = chars[i];
+            match letter {
+                '\'' => quoted = !quoted,
+                '\\' if !quoted && i + 1 < chars.len() => {
+                    out.push(letter);
+                    out.push(chars[i + 1]);
+                    i += 2;
+                    continue;
+                },
+                '!' if !quoted && chars.get(i + 1) == Some(&'!') => {
+                    out += &self.get(-1).with_context(|| "!!: event not found")?.command;
+                    i += 2;
+                    continue;
+                },
+                '!' if !quoted && matches!(chars.get(i + 1), Some('0'..='9' | '-')) => {
+                    let mut x = i + 1;
+                    if chars[x] == '-' { x += 1; }
+                    while x < chars.len() && chars[x].is_ascii_digit() { x += 1; }
+                    let event: String = chars[i + 1..x].iter().collect();
+                    // ! followed by - without a number is kept
+                    if let Ok(num) = event.parse::<i64>() {
+                        match self.get(num) {
+                            Some(entry) if num != 0 => out += &entry.command,
+                            _ => bail!("!{}: event not found", event)
+                        }
+                        i = x;
+                        continue;
+                    }
+                },
+                _ => {}
+            }
+            out.push(letter);
+            i += 1;
+        }
+        Ok(out)
+    }
+
+    /// Command starting with the input to complete it with, preferring commands run in the directory, then the most
+    /// frequent and then the newest one
+    pub fn suggest(&self, input: &str, dir: Option<&str>) -> Option<&str> {
+        if input.is_empty() {
+            return None;
+        }
+        self.entries.iter().enumerate()
+            .filter(|(_, entry)| entry.command.len() > input.len() && entry.command.starts_with(input))
+            .max_by_key(|(i, entry)| (dir.is_some() && entry.dir.as_deref() == dir, entry.count, *i))
+            .map(|(_, entry)| entry.command.as_str())
+    }
+
+    /// Index of the newest entry before start containing the query
+    pub fn search(&self, query: &str, start: usize) -> Option<usize> {
+        self.entries[..start.min(self.entries.len())].iter().rposition(|entry| entry.command.contains(query))
+    }
+}
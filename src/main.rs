@@ -1,26 +1,46 @@
 mod parser;
 mod env;
+mod completion;
+mod highlight;
+mod history;
+#[allow(non_snake_case)]
 mod nativeFunctions;
+mod prompt;
 
-use std::io::{self, BufRead, Stdout, Write};
-use std::cmp;
-use std::convert::TryInto;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read, Write};
+use std::os::fd::AsRawFd;
 use std::path::Path;
-use std::process;
 use clap::{Command, arg};
-use termion::raw::{IntoRawMode, RawTerminal};
-use termion::input::TermRead;
-use termion::cursor::{DetectCursorPos};
+use termion::raw::IntoRawMode;
 use termion::event::*;
 use std::fs::File;
 use std::io::BufReader;
 use anyhow::Result;
+use os_pipe::PipeReader;
+use crate::completion::{Candidate, Completion};
+use crate::highlight::Highlighter;
+use crate::history::History;
 use crate::nativeFunctions::get_native_functions;
 use crate::parser::vars::Variable;
+use crate::prompt::Prompt;
+
+/// Rows of completion candidates shown below the input at once
+const MENU_ROWS: usize = 10;
 
 struct Term {
     input: String,
+    /// cursor position in chars
     idx: usize,
+    /// row of the cursor below the first row of the prompt, as last drawn
+    cursor_row: usize,
+    /// completion candidates shown below the input
+    menu: Vec<Candidate>,
+    /// candidate selected in the menu with Tab
+    selected: Option<usize>,
+    highlighter: Highlighter,
+    /// rest of a command from the history starting with the input, shown greyed out after it
+    suggestion: String,
 }
 
 impl Term {
@@ -28,130 +48,586 @@ impl Term {
         Term {
             input: String::new(),
             idx: 0,
+            cursor_row: 0,
+            menu: Vec::new(),
+            selected: None,
+            highlighter: Highlighter::default(),
+            suggestion: String::new(),
         }
     }
 
-    fn print(self: &Self, stdout: &mut RawTerminal<Stdout>) {
-        print!("{}", self.format(stdout));
+    fn len(&self) -> usize {
+        self.input.chars().count()
     }
-    fn format(self: &Self, stdout: &mut RawTerminal<Stdout>) -> String {
-        let (_, y) = stdout.cursor_pos().unwrap();
-        format!(
-            "{}{}{}{}{}{}",
-            termion::clear::CurrentLine,
-            termion::cursor::Goto(1, y),
-            "$: ",
-            &self.input,
-            termion::cursor::Left((self.input.len() - self.idx).try_into().unwrap()),
-            termion::cursor::Right(if self.input.len() > 0 { 1 } else { 0 } )
-        )
+
+    /// Byte offset of a char position in the input
+    fn byte_idx(&self, idx: usize) -> usize {
+        self.input.char_indices().nth(idx).map(|(i, _)| i).unwrap_or(self.input.len())
+    }
+
+    /// Row and column of a char position in the input and suggestion, wrapping at the terminal width. Lines after the
+    /// first start after the continuation prompt
+    fn position(&self, prompt: &Prompt, idx: usize, width: usize) -> (usize, usize) {
+        let (mut row, mut col) = prompt.end(width);
+        let continuation = prompt::width(&prompt.continuation);
+        for letter in self.input.chars().chain(self.suggestion.chars()).take(idx) {
+            if letter == '\n' {
+                row += 1 + continuation / width;
+                col = continuation % width;
+                continue;
+            }
+            col += 1;
+            if col == width {
+                row += 1;
+                col = 0;
+            }
+        }
+        (row, col)
+    }
+
+    /// Redraws the prompt and input from the first row of the prompt, then places the cursor
+    fn format(&mut self, prompt: &Prompt, width: usize) -> String {
+        let mut out = String::new();
+        if self.cursor_row > 0 {
+            out += &termion::cursor::Up(self.cursor_row as u16).to_string();
+        }
+        out += "\r";
+        out += termion::clear::AfterCursor.as_ref();
+        out += &prompt.left.replace('\n', "\r\n");
+        let start = prompt.end(width);
+        // the right prompt is left out once the first line of input reaches it
+        let first_line = self.input.chars().chain(self.suggestion.chars()).take_while(|letter| *letter != '\n').count();
+        let right_width = prompt::width(&prompt.right);
+        if right_width > 0 && !prompt.right.contains('\n') && start.1 + first_line + right_width < width {
+            out += &termion::cursor::Right((width - right_width - start.1) as u16).to_string();
+            out += &prompt.right;
+            out += "\r";
+            if start.1 > 0 {
+                out += &termion::cursor::Right(start.1 as u16).to_string();
+            }
+        }
+        let newline = format!("\r\n{}", prompt.continuation);
+        out += &self.highlighter.highlight(&self.input).replace('\n', &newline);
+        if !self.suggestion.is_empty() {
+            let grey = termion::color::Fg(termion::color::LightBlack);
+            out += &format!("{}{}{}", grey, self.suggestion.replace('\n', &newline), termion::color::Fg(termion::color::Reset));
+        }
+        let end = self.position(prompt, self.len() + self.suggestion.chars().count(), width);
+        // the terminal only wraps once the next letter is printed
+        let last = self.suggestion.chars().last().or(self.input.chars().last());
+        if end.1 == 0 && end.0 > 0 && last != Some('\n') {
+            out += "\r\n";
+        }
+        let mut end_row = end.0;
+        for line in self.format_menu(width) {
+            out += "\r\n";
+            out += &line;
+            end_row += 1;
+        }
+        let target = self.position(prompt, self.idx, width);
+        if end_row > target.0 {
+            out += &termion::cursor::Up((end_row - target.0) as u16).to_string();
+        }
+        out += "\r";
+        if target.1 > 0 {
+            out += &termion::cursor::Right(target.1 as u16).to_string();
+        }
+        self.cursor_row = target.0;
+        out
     }
 
-    fn insert(self: &mut Self, idx: usize, char: char) {
-        self.input.insert(idx, char);
+    /// Lines of the completion menu, in columns fitting the terminal width, or one candidate per line when they have
+    /// descriptions. The rows scroll to show the selected candidate
+    fn format_menu(&self, width: usize) -> Vec<String> {
+        if self.menu.is_empty() {
+            return Vec::new();
+        }
+        let col_width = (self.menu.iter().map(|item| item.display.chars().count()).max().unwrap_or(0) + 2).min(width);
+        let described = self.menu.iter().any(|item| item.description.is_some());
+        let cols = if described { 1 } else { (width / col_width).max(1) };
+        let rows = self.menu.len().div_ceil(cols);
+        let shown = rows.min(MENU_ROWS);
+        let first = self.selected.map(|selected| (selected / cols + 1).saturating_sub(shown)).unwrap_or(0);
+        let mut lines = Vec::new();
+        for row in first..first + shown {
+            let mut line = String::new();
+            for (i, item) in self.menu.iter().enumerate().skip(row * cols).take(cols) {
+                let display: String = item.display.chars().take(col_width.saturating_sub(1)).collect();
+                let padding = " ".repeat(col_width - display.chars().count());
+                if self.selected == Some(i) {
+                    line += &format!("{}{}{}{}", termion::style::Invert, display, termion::style::Reset, padding);
+                } else {
+                    line += &format!("{}{}", display, padding);
+                }
+                if let Some(description) = &item.description {
+                    let description: String = description.chars().take(width - col_width).collect();
+                    line += &format!("{}{}{}", termion::style::Faint, description, termion::style::Reset);
+                }
+            }
+            lines.push(line.trim_end().to_string());
+        }
+        if rows > shown {
+            lines.push(format!("{} more rows", rows - shown));
+        }
+        lines
     }
-    fn insert_str(self: &mut Self, idx: usize, str: &str) {
-        self.input.insert_str(idx, str);
+
+    fn print(&mut self, prompt: &Prompt, stdout: &mut impl Write) -> io::Result<()> {
+        let width = termion::terminal_size().map(|(width, _)| width as usize).unwrap_or(80).max(1);
+        write!(stdout, "{}", self.format(prompt, width))?;
+        stdout.flush()
     }
-    fn remove(self: &mut Self, idx: usize) {
-        self.input.remove(idx);
+
+    fn insert(&mut self, char: char) {
+        let at = self.byte_idx(self.idx);
+        self.input.insert(at, char);
+        self.idx += 1;
+    }
+
+    fn insert_str(&mut self, str: &str) {
+        let at = self.byte_idx(self.idx);
+        self.input.insert_str(at, str);
+        self.idx += str.chars().count();
+    }
+
+    /// Inserts the suggestion, or only up to the end of its next word
+    fn accept_suggestion(&mut self, word: bool) {
+        let chars: Vec<char> = self.suggestion.chars().collect();
+        let mut end = chars.len();
+        if word {
+            end = chars.iter().position(|letter| letter.is_alphanumeric()).unwrap_or(end);
+            end += chars[end..].iter().position(|letter| !letter.is_alphanumeric()).unwrap_or(chars.len() - end);
+        }
+        let accepted: String = chars[..end].iter().collect();
+        self.suggestion = chars[end..].iter().collect();
+        self.insert_str(&accepted);
+    }
+
+    /// Removes the chars between two positions and moves the cursor to the start
+    fn remove(&mut self, start: usize, end: usize) {
+        let range = self.byte_idx(start)..self.byte_idx(end);
+        self.input.replace_range(range, "");
+        self.idx = start;
+    }
+
+    /// Replaces the input, with the cursor at the end
+    fn set_input(&mut self, input: &str) {
+        self.input = input.to_string();
+        self.idx = self.len();
+    }
+
+    fn clear(&mut self) {
+        self.input.clear();
+        self.suggestion.clear();
+        self.idx = 0;
+        self.cursor_row = 0;
+    }
+
+    /// Start of the word before the cursor
+    fn word_start(&self, is_word: fn(&char) -> bool) -> usize {
+        let chars: Vec<char> = self.input.chars().collect();
+        let mut i = self.idx;
+        while i > 0 && !is_word(&chars[i - 1]) { i -= 1; }
+        while i > 0 && is_word(&chars[i - 1]) { i -= 1; }
+        i
+    }
+
+    /// End of the word after the cursor
+    fn word_end(&self, is_word: fn(&char) -> bool) -> usize {
+        let chars: Vec<char> = self.input.chars().collect();
+        let mut i = self.idx;
+        while i < chars.len() && !is_word(&chars[i]) { i += 1; }
+        while i < chars.len() && is_word(&chars[i]) { i += 1; }
+        i
+    }
+}
+
+/// State of the Ctrl-R reverse history search
+struct Search {
+    query: String,
+    /// index of the matching history entry
+    found: Option<usize>,
+    /// input before the search started, restored when it's cancelled
+    draft: String
+}
+
+impl Search {
+    /// The shell prompt, showing the query in place of the left prompt
+    fn prompt(&self, prompt: &Prompt) -> Prompt {
+        let failed = if self.found.is_none() && !self.query.is_empty() { "failed " } else { "" };
+        Prompt { left: format!("({}reverse-i-search)'{}': ", failed, self.query), ..prompt.clone() }
+    }
+}
+
+/// What the line editor waited for
+enum Input {
+    Key(Event),
+    /// a command lookup of the highlighter finished, the input is drawn again with its colours
+    Redraw,
+    Closed
+}
+
+/// Reads key events from stdin without buffering them in the process, so they can be waited for together with the
+/// highlighter
+#[derive(Default)]
+struct Keys {
+    pending: VecDeque<u8>
+}
+
+impl Keys {
+    /// Reads what's available on stdin, returning false at its end
+    fn fill(&mut self) -> Result<bool> {
+        let mut buf = [0u8; 64];
+        let read = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if read < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted { return Ok(true) }
+            return Err(err.into());
+        }
+        self.pending.extend(&buf[..read as usize]);
+        Ok(read > 0)
+    }
+
+    /// Waits for the next key, or for wake to become readable
+    fn next(&mut self, wake: Option<&PipeReader>) -> Result<Input> {
+        if self.pending.is_empty() {
+            let mut fds = vec![libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 }];
+            fds.extend(wake.map(|wake| libc::pollfd { fd: wake.as_raw_fd(), events: libc::POLLIN, revents: 0 }));
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+                let err = io::Error::last_os_error();
+                // a signal like SIGWINCH, drawing again doesn't hurt
+                if err.kind() == io::ErrorKind::Interrupted { return Ok(Input::Redraw) }
+                return Err(err.into());
+            }
+            if let (Some(mut wake), Some(fd)) = (wake, fds.get(1)) {
+                if fd.revents != 0 {
+                    // emptied at once, lookups finishing together need a single redraw
+                    let _ = wake.read(&mut [0; 64]);
+                    return Ok(Input::Redraw);
+                }
+            }
+            if !self.fill()? { return Ok(Input::Closed) }
+        }
+        let first = match self.pending.pop_front() {
+            Some(first) => first,
+            None => return Ok(Input::Redraw)
+        };
+        // an escape read on its own is the Esc key, otherwise it starts a sequence
+        if first == b'\x1b' && self.pending.is_empty() {
+            return Ok(Input::Key(Event::Key(Key::Esc)));
+        }
+        let mut bytes = std::iter::from_fn(|| {
+            while self.pending.is_empty() {
+                if !matches!(self.fill(), Ok(true)) { return None }
+            }
+            self.pending.pop_front().map(Ok)
+        });
+        Ok(Input::Key(parse_event(first, &mut bytes)?))
     }
 }
 
 struct Shell {
     term: Term,
     ctx: parser::vars::Context,
+    /// rendered before each command is read
+    prompt: Prompt,
 }
 
 impl Shell {
     fn new() -> Shell {
         Shell {
             term: Term::new(),
-            ctx: parser::vars::Context::new()
+            ctx: parser::vars::Context::new(),
+            prompt: Prompt::new()
         }
     }
 
-    fn collect(&mut self) {
-        let stdin = std::io::stdin();
-        let v = stdin.lock().lines().next().unwrap().unwrap();
-        self.term.input = v;
+    /// Renders the prompt, falling back to the default one if a command in it fails
+    fn render_prompt(&mut self) {
+        self.prompt = Prompt::render(&mut self.ctx).unwrap_or_else(|err| {
+            eprintln!("rush: prompt: {}", err);
+            Prompt::new()
+        });
     }
 
+    /// Reads a line without editing, when stdin isn't a terminal. Returns false at the end of input
+    fn collect(&mut self) -> Result<bool> {
+        self.render_prompt();
+        print!("{}", self.prompt.left);
+        io::stdout().flush()?;
+        let stdin = std::io::stdin();
+        match stdin.lock().lines().next() {
+            Some(line) => {
+                self.term.input = line?;
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
 
-    fn edit(&mut self) {
-        let stdin = io::stdin();
-        let mut stdout = io::stdout().into_raw_mode().unwrap();
-        for c in stdin.keys() {
-            let c = c.unwrap();
-            match c {
-                Key::Char('\n') => {
-                    if self.term.input.chars().nth(self.term.idx).unwrap_or(' ') == '\\' {
-                        self.term.insert_str(self.term.idx, "\\\n");
-                    } else {
-                        break;
-                    }
+    /// Reads a line with the line editor in raw mode. Returns false on Ctrl-D in an empty line
+    fn edit(&mut self) -> Result<bool> {
+        // the prompt can run commands, which expect the terminal as usual
+        self.render_prompt();
+        let mut stdout = io::stdout().into_raw_mode()?;
+        self.term.clear();
+        self.term.highlighter.refresh(&self.ctx);
+        self.term.print(&self.prompt, &mut stdout)?;
+        let alphanumeric: fn(&char) -> bool = |letter| letter.is_alphanumeric();
+        // position in the history while browsing with Up and Down, entries.len() is the line being written
+        let mut history_idx = self.ctx.history.entries.len();
+        let mut draft = String::new();
+        let mut search: Option<Search> = None;
+        // candidates shown in the menu, cycled through with Tab
+        let mut completion: Option<Completion> = None;
+        let dir = std::env::current_dir().ok().map(|dir| dir.to_string_lossy().to_string());
+        let mut open = true;
+        let mut keys = Keys::default();
+        loop {
+            let event = match keys.next(self.term.highlighter.wake())? {
+                Input::Key(event) => event,
+                Input::Redraw => {
+                    let prompt = search.as_ref().map(|current| current.prompt(&self.prompt)).unwrap_or_else(|| self.prompt.clone());
+                    self.term.print(&prompt, &mut stdout)?;
+                    continue;
+                },
+                Input::Closed => break
+            };
+            let term = &mut self.term;
+            if completion.is_some() && !matches!(event, Event::Key(Key::Char('\t')) | Event::Key(Key::BackTab)) {
+                completion = None;
+                term.menu.clear();
+                // Enter keeps the selected candidate without running the command
+                if term.selected.take().is_some() && event == Event::Key(Key::Char('\n')) {
+                    term.print(&self.prompt, &mut stdout)?;
+                    continue;
                 }
-                Key::Backspace => {
-                    if !self.term.input.is_empty() && self.term.idx > 0 {
-                        if self.term.idx == self.term.input.len() - 1 {
-                            self.term.input.pop();
-                        } else {
-                            self.term.remove(self.term.idx - 1);
+            }
+            if let Some(current) = &mut search {
+                let mut cancel = false;
+                let handled = match event {
+                    Event::Key(Key::Ctrl('r')) => {
+                        let start = current.found.unwrap_or(self.ctx.history.entries.len());
+                        if let Some(found) = self.ctx.history.search(&current.query, start) {
+                            current.found = Some(found);
                         }
-                        self.term.idx -= 1;
-                    }
-                }
-                Key::Delete => {
-                    if self.term.idx < self.term.input.len() {
-                        self.term.remove(self.term.idx);
-                    }
+                        true
+                    },
+                    Event::Key(Key::Char(char)) if char != '\n' && char != '\t' => {
+                        current.query.push(char);
+                        let start = current.found.map(|found| found + 1).unwrap_or(self.ctx.history.entries.len());
+                        current.found = self.ctx.history.search(&current.query, start);
+                        true
+                    },
+                    Event::Key(Key::Backspace) => {
+                        current.query.pop();
+                        current.found = self.ctx.history.search(&current.query, self.ctx.history.entries.len());
+                        true
+                    },
+                    Event::Key(Key::Ctrl('g')) | Event::Key(Key::Ctrl('c')) => {
+                        cancel = true;
+                        true
+                    },
+                    _ => false
+                };
+                match current.found {
+                    _ if cancel => term.set_input(&current.draft),
+                    Some(found) => term.set_input(&self.ctx.history.entries[found].command),
+                    None if current.query.is_empty() => term.set_input(&current.draft),
+                    None => {}
                 }
-                Key::End => {
-                    self.term.idx = cmp::max(self.term.input.len(), 1) - 1;
+                if cancel {
+                    search = None;
                 }
-                Key::Home => {
-                    self.term.idx = 0;
+                if handled {
+                    let prompt = search.as_ref().map(|current| current.prompt(&self.prompt)).unwrap_or_else(|| self.prompt.clone());
+                    term.print(&prompt, &mut stdout)?;
+                    continue;
                 }
-                Key::Left => {
-                    if self.term.idx > 0 {
-                        self.term.idx -= 1;
+                // other keys end the search, keeping the found command to edit or run
+                search = None;
+            }
+            match event {
+                Event::Key(Key::Char('\n')) => {
+                    // a backslash at the end continues the command on the next line
+                    if term.idx == term.len() && term.input.ends_with('\\') {
+                        term.insert('\n');
+                    } else {
+                        break;
                     }
-                }
-                Key::Right => {
-                    if self.term.idx < self.term.input.len() - 1 {
-                        self.term.idx += 1;
+                },
+                Event::Key(Key::Char('\t')) | Event::Key(Key::BackTab) => match &completion {
+                    Some(active) => {
+                        let count = active.candidates.len();
+                        let selected = match (term.selected, event == Event::Key(Key::BackTab)) {
+                            (None, false) => 0,
+                            (None, true) => count - 1,
+                            (Some(selected), false) => (selected + 1) % count,
+                            (Some(selected), true) => (selected + count - 1) % count
+                        };
+                        term.selected = Some(selected);
+                        term.remove(active.start, term.idx);
+                        term.insert_str(&active.candidates[selected].value);
+                    },
+                    None => {
+                        let found = completion::complete(&mut self.ctx, &term.input, term.idx);
+                        if let [candidate] = found.candidates.as_slice() {
+                            term.remove(found.start, term.idx);
+                            term.insert_str(&candidate.value);
+                            if !candidate.value.ends_with('/') {
+                                term.insert(' ');
+                            }
+                        } else if !found.candidates.is_empty() {
+                            // the common prefix is inserted first, the menu is shown once there's nothing more to insert
+                            let prefix = completion::common_prefix(&found.candidates);
+                            if prefix.chars().count() > term.idx - found.start {
+                                term.remove(found.start, term.idx);
+                                term.insert_str(&prefix);
+                            } else {
+                                term.menu = found.candidates.clone();
+                                completion = Some(found);
+                            }
+                        }
                     }
-                }
-                Key::Ctrl('c') => {
-                    process::exit(1);
-                }
-                Key::Ctrl('d') => {
-                    process::exit(0);
-                }
-                Key::Char(char) => {
-                    self.term.insert(self.term.idx, char);
-                    self.term.idx += 1;
-                }
+                },
+                Event::Key(Key::Char(char)) => term.insert(char),
+                Event::Key(Key::Backspace) | Event::Key(Key::Ctrl('h')) if term.idx > 0 => term.remove(term.idx - 1, term.idx),
+                Event::Key(Key::Delete) if term.idx < term.len() => term.remove(term.idx, term.idx + 1),
+                Event::Key(Key::Ctrl('d')) => if term.input.is_empty() {
+                    open = false;
+                    break;
+                } else if term.idx < term.len() {
+                    term.remove(term.idx, term.idx + 1);
+                },
+                Event::Key(Key::Left) | Event::Key(Key::Ctrl('b')) => term.idx = term.idx.saturating_sub(1),
+                // the suggestion is only shown with the cursor at the end, that's where Right and End accept it
+                Event::Key(Key::Right) | Event::Key(Key::Ctrl('f')) => if term.idx == term.len() {
+                    term.accept_suggestion(false);
+                } else {
+                    term.idx += 1;
+                },
+                Event::Key(Key::Home) | Event::Key(Key::Ctrl('a')) => term.idx = 0,
+                Event::Key(Key::End) | Event::Key(Key::Ctrl('e')) => {
+                    term.idx = term.len();
+                    term.accept_suggestion(false);
+                },
+                Event::Key(Key::Up) | Event::Key(Key::Ctrl('p')) if history_idx > 0 => {
+                    if history_idx == self.ctx.history.entries.len() {
+                        draft = term.input.clone();
+                    }
+                    history_idx -= 1;
+                    term.set_input(&self.ctx.history.entries[history_idx].command);
+                },
+                Event::Key(Key::Down) | Event::Key(Key::Ctrl('n')) if history_idx < self.ctx.history.entries.len() => {
+                    history_idx += 1;
+                    match self.ctx.history.entries.get(history_idx) {
+                        Some(entry) => term.set_input(&entry.command),
+                        None => term.set_input(&draft)
+                    }
+                },
+                Event::Key(Key::Ctrl('r')) => search = Some(Search { query: String::new(), found: None, draft: term.input.clone() }),
+                Event::Key(Key::Alt('b')) => term.idx = term.word_start(alphanumeric),
+                Event::Key(Key::Alt('f')) => if term.idx == term.len() {
+                    term.accept_suggestion(true);
+                } else {
+                    term.idx = term.word_end(alphanumeric);
+                },
+                // Ctrl-Left and Ctrl-Right (or Alt), which termion doesn't parse
+                Event::Unsupported(seq) => match seq.as_slice() {
+                    b"\x1b[1;5D" | b"\x1b[1;3D" => term.idx = term.word_start(alphanumeric),
+                    b"\x1b[1;5C" | b"\x1b[1;3C" => if term.idx == term.len() {
+                        term.accept_suggestion(true);
+                    } else {
+                        term.idx = term.word_end(alphanumeric);
+                    },
+                    _ => {}
+                },
+                Event::Key(Key::Ctrl('k')) => term.remove(term.idx, term.len()),
+                Event::Key(Key::Ctrl('u')) => term.remove(0, term.idx),
+                Event::Key(Key::Ctrl('w')) => term.remove(term.word_start(|letter| !letter.is_whitespace()), term.idx),
+                Event::Key(Key::Ctrl('l')) => {
+                    write!(stdout, "{}{}", termion::clear::All, termion::cursor::Goto(1, 1))?;
+                    term.cursor_row = 0;
+                },
+                Event::Key(Key::Ctrl('c')) => {
+                    term.idx = term.len();
+                    term.suggestion.clear();
+                    term.print(&self.prompt, &mut stdout)?;
+                    write!(stdout, "^C\r\n")?;
+                    term.clear();
+                    history_idx = self.ctx.history.entries.len();
+                },
                 _ => {}
             }
-            self.term.print(&mut stdout);
-            stdout.flush().unwrap();
+            term.suggestion = match self.ctx.history.suggest(&term.input, dir.as_deref()) {
+                Some(command) if search.is_none() && completion.is_none() && term.idx == term.len() => {
+                    command[term.input.len()..].to_string()
+                },
+                _ => String::new()
+            };
+            let prompt = search.as_ref().map(|current| current.prompt(&self.prompt)).unwrap_or_else(|| self.prompt.clone());
+            term.print(&prompt, &mut stdout)?;
         }
-        stdout.suspend_raw_mode().unwrap();
+        // the output of the command starts below the whole input
+        self.term.suggestion.clear();
+        self.term.idx = self.term.len();
+        self.term.print(&self.prompt, &mut stdout)?;
+        write!(stdout, "\r\n")?;
+        stdout.flush()?;
+        Ok(open)
     }
 
     fn start() {
         let mut shell = Shell::new();
         shell.ctx.native_func = get_native_functions();
+        // exports start out as the environment, commands get them from the context from then on
+        shell.ctx.exports = env::os_env_hashmap().into_iter().map(|(k, v)| (k, Variable::String(v))).collect();
+        let interactive = termion::is_tty(&io::stdin());
+        if interactive {
+            if let Some(path) = History::default_path() {
+                match History::load(path, history::limit(&mut shell.ctx)) {
+                    Ok(history) => shell.ctx.history = history,
+                    Err(err) => eprintln!("rush: {:#}", err)
+                }
+            }
+        }
         loop {
-            print!("$: ");
-            io::stdout().flush().unwrap();
-            shell.collect();
+            // like other shells, jobs that finished are reported before the next prompt
+            match shell.ctx.reap_jobs() {
+                Ok(done) => done.iter().for_each(|job| println!("{}", job)),
+                Err(err) => eprintln!("rush: {}", err)
+            }
+            let res = if interactive { shell.edit() } else { shell.collect() };
+            match res {
+                Ok(true) => {},
+                Ok(false) => break,
+                Err(err) => {
+                    eprintln!("rush: {}", err);
+                    break;
+                }
+            }
+            if interactive {
+                match shell.ctx.history.expand(&shell.term.input) {
+                    Ok(line) if line != shell.term.input => {
+                        println!("{}", line);
+                        shell.term.input = line;
+                    },
+                    Ok(_) => {},
+                    Err(err) => {
+                        eprintln!("rush: {}", err);
+                        continue;
+                    }
+                }
+                // HISTSIZE can be changed while the shell runs
+                shell.ctx.history.limit = history::limit(&mut shell.ctx);
+                if let Err(err) = shell.ctx.history.add(&shell.term.input) {
+                    eprintln!("rush: {:#}", err);
+                }
+            }
             if shell.term.input == "exit" {
                 break;
             }
             shell.term.input += "\n";
-            shell.ctx.exports = env::os_env_hashmap().into_iter().map(|(k, v)| (k, Variable::String(v))).collect();
             let res = parser::exec(&mut shell.term.input.as_bytes(), &mut shell.ctx);
             if let Err(err) = res { eprintln!("rush: {}", err) }
         }
@@ -164,6 +640,7 @@ const DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
 
 fn load_and_run<P: AsRef<Path>>(path: P) -> Result<()> {
     let mut ctx = parser::vars::Context::new();
+    ctx.native_func = get_native_functions();
     let src = File::open(path).unwrap();
     parser::exec(&mut BufReader::new(src), &mut ctx)
 }
@@ -184,6 +661,7 @@ fn main() {
 
     if let Some(command) = matches.value_of("command") {
         let mut ctx = parser::vars::Context::new();
+        ctx.native_func = get_native_functions();
         parser::exec(&mut command.as_bytes(), &mut ctx).unwrap();
         return;
     };
@@ -196,8 +674,33 @@ fn main() {
 
 #[cfg(test)]
 mod test {
-    use crate::{load_and_run};
+    use crate::{get_native_functions, load_and_run, parser, Term, Shell};
+    use crate::highlight::Highlighter;
+    use crate::prompt::Prompt;
+    use crate::completion::{complete, common_prefix, Completion};
+    use crate::parser::vars::Variable;
+    use crate::history::{self, History};
     use anyhow::Result;
+    use std::fs::File;
+    use std::io::{BufReader, Read};
+    use std::path::PathBuf;
+    /// Directory for the files of a test, removed with them when the test ends, also when it fails
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("rush-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
     #[test]
     fn simple() -> Result<()> {
         load_and_run("test/simple.rush")
@@ -222,4 +725,407 @@ mod test {
     fn while_expr() -> Result<()> {
         load_and_run("test/while.rush")
     }
+
+    #[test]
+    fn for_range() -> Result<()> {
+        load_and_run("test/range.rush")
+    }
+
+    #[test]
+    fn continue_expr() -> Result<()> {
+        load_and_run("test/continue.rush")
+    }
+
+    #[test]
+    fn builtins() -> Result<()> {
+        load_and_run("test/builtins.rush")
+    }
+
+    #[test]
+    fn test_builtin() -> Result<()> {
+        load_and_run("test/test.rush")?;
+        let mut ctx = parser::vars::Context::new();
+        ctx.native_func = get_native_functions();
+        parser::exec(&mut "test 10 \"<\" 9\nlet strings = $?\ntest 10 -lt 9\nlet numbers = $?\n".as_bytes(), &mut ctx)?;
+        assert_eq!(ctx.get_var("strings").map(|var| var.to_string()).as_deref(), Some("0"));
+        assert_eq!(ctx.get_var("numbers").map(|var| var.to_string()).as_deref(), Some("1"));
+        Ok(())
+    }
+
+    #[test]
+    fn dirs() -> Result<()> {
+        let mut ctx = parser::vars::Context::new();
+        ctx.native_func = get_native_functions();
+        parser::exec(&mut BufReader::new(File::open("test/dirs.rush")?), &mut ctx)?;
+        let results = ctx.get_var("results").map(|var| var.to_string()).unwrap_or_default();
+        assert_eq!(results.lines().filter(|line| *line == "ok").count(), 7, "{}", results);
+        Ok(())
+    }
+
+    #[test]
+    fn function() -> Result<()> {
+        load_and_run("test/function.rush")
+    }
+
+    #[test]
+    fn math() -> Result<()> {
+        load_and_run("test/math.rush")
+    }
+
+    #[test]
+    fn index() -> Result<()> {
+        load_and_run("test/index.rush")
+    }
+
+    #[test]
+    fn typed_let() -> Result<()> {
+        load_and_run("test/typed_let.rush")
+    }
+
+    #[test]
+    fn redirect() -> Result<()> {
+        load_and_run("test/redirect.rush")
+    }
+
+    #[test]
+    fn redirect_close() -> Result<()> {
+        let mut ctx = parser::vars::Context::new();
+        ctx.native_func = get_native_functions();
+        let script = "let fds = $(sh -c 'test -e /proc/self/fd/2 && echo open || echo closed' 2>&-)\n";
+        parser::exec(&mut script.as_bytes(), &mut ctx)?;
+        assert_eq!(ctx.get_var("fds").map(|fds| fds.to_string()).as_deref(), Some("closed"));
+        Ok(())
+    }
+
+    #[test]
+    fn words() -> Result<()> {
+        load_and_run("test/words.rush")
+    }
+
+    #[test]
+    fn heredoc() -> Result<()> {
+        load_and_run("test/heredoc.rush")
+    }
+
+    #[test]
+    fn substitution() -> Result<()> {
+        load_and_run("test/substitution.rush")
+    }
+
+    #[test]
+    fn pipeline() -> Result<()> {
+        load_and_run("test/pipeline.rush")
+    }
+
+    #[test]
+    fn glob() -> Result<()> {
+        load_and_run("test/glob.rush")
+    }
+
+    #[test]
+    fn parameter() -> Result<()> {
+        load_and_run("test/parameter.rush")?;
+        let mut ctx = parser::vars::Context::new();
+        for script in ["echo ${#}\n", "echo ${#foo-bar}\n"] {
+            let err = parser::exec(&mut script.as_bytes(), &mut ctx).unwrap_err();
+            assert!(err.to_string().starts_with("Bad substitution"), "{}", err);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn splat() -> Result<()> {
+        load_and_run("test/splat.rush")
+    }
+
+    #[test]
+    fn capture() -> Result<()> {
+        load_and_run("test/capture.rush")
+    }
+
+    #[test]
+    fn jobs() -> Result<()> {
+        load_and_run("test/jobs.rush")
+    }
+
+    #[test]
+    fn function_errors() -> Result<()> {
+        let mut ctx = parser::vars::Context::new();
+        ctx.native_func = get_native_functions();
+        let script = "function fail\n    echo ${nope:?bad}\nend\nlet x = $(fail)\n";
+        assert!(parser::exec(&mut script.as_bytes(), &mut ctx).is_err());
+        assert_eq!(ctx.scopes.len(), 1);
+        parser::exec(&mut "fail > /dev/null\n".as_bytes(), &mut ctx).unwrap_err();
+        parser::exec(&mut "let y = $(echo still running)\n".as_bytes(), &mut ctx)?;
+        assert_eq!(ctx.get_var("y").map(|y| y.to_string()).as_deref(), Some("still running"));
+        // a function body isn't inside the caller's loop
+        let script = "function stop\n    break\nend\nfor i in 1..3\n    stop\n    let after = $i\nend\n";
+        let err = parser::exec(&mut script.as_bytes(), &mut ctx).unwrap_err();
+        assert_eq!(err.to_string(), "Too many break statements");
+        assert!(ctx.get_var("after").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn array_capture_words() -> Result<()> {
+        let mut ctx = parser::vars::Context::new();
+        ctx.native_func = get_native_functions();
+        let script = "let quoted = \"me@(home)\"\nlet word = $(echo a@(echo b))\nlet lines = @(seq 2)\n";
+        parser::exec(&mut script.as_bytes(), &mut ctx)?;
+        assert_eq!(ctx.get_var("quoted").map(|var| var.to_string()).as_deref(), Some("me@(home)"));
+        assert_eq!(ctx.get_var("word").map(|var| var.to_string()).as_deref(), Some("a@(echo b)"));
+        assert_eq!(ctx.get_var("lines").map(|var| var.to_string()).as_deref(), Some("1 2"));
+        Ok(())
+    }
+
+    #[test]
+    fn substitution_fds() -> Result<()> {
+        let mut ctx = parser::vars::Context::new();
+        ctx.native_func = get_native_functions();
+        // only cat gets the descriptor, ls sees 0, 1, 2 and its own directory
+        let script = "let others = $(cat <(echo a) | ls /proc/self/fd | wc -l)\n";
+        parser::exec(&mut script.as_bytes(), &mut ctx)?;
+        assert_eq!(ctx.get_var("others").map(|var| var.to_string()).as_deref(), Some("4"));
+        Ok(())
+    }
+
+    #[test]
+    fn pipeline_functions() -> Result<()> {
+        let mut ctx = parser::vars::Context::new();
+        ctx.native_func = get_native_functions();
+        // far more than a pipe buffer, the stages have to run at the same time
+        let script = "function producer
+                seq 100000
+            end
+            function counter
+                wc -l
+            end
+            let lines = $(producer | counter)
+            producer | counter > /dev/null
+            let statuses = @PIPESTATUS\n";
+        parser::exec(&mut script.as_bytes(), &mut ctx)?;
+        assert_eq!(ctx.get_var("lines").map(|var| var.to_string()).as_deref(), Some("100000"));
+        assert_eq!(ctx.get_var("statuses").map(|var| var.to_string()).as_deref(), Some("0 0"));
+        Ok(())
+    }
+
+    #[test]
+    fn builtin_errors() -> Result<()> {
+        let mut ctx = parser::vars::Context::new();
+        ctx.native_func = get_native_functions();
+        let script = "cd /nonexistent 2> /dev/null || let fallback = yes
+            test 1 -eq x 2> /dev/null
+            let code = $?
+            let message = $(cd /nonexistent 2>&1)
+            if cd /nonexistent 2> /dev/null
+                echo unreachable
+            end\n";
+        parser::exec(&mut script.as_bytes(), &mut ctx)?;
+        assert_eq!(ctx.get_var("fallback").map(|var| var.to_string()).as_deref(), Some("yes"));
+        assert_eq!(ctx.get_var("code").map(|var| var.to_string()).as_deref(), Some("1"));
+        assert_eq!(ctx.get_var("message").map(|var| var.to_string()).as_deref(), Some("rush: cd: /nonexistent: No such file or directory"));
+        Ok(())
+    }
+
+    #[test]
+    fn reap_jobs() -> Result<()> {
+        let mut ctx = parser::vars::Context::new();
+        ctx.native_func = get_native_functions();
+        parser::exec(&mut "sleep 0 &\nsleep 5 &\n".as_bytes(), &mut ctx)?;
+        ctx.jobs[0].wait()?;
+        assert_eq!(ctx.reap_jobs()?, vec!["[1]  Done\tsleep 0"]);
+        assert_eq!(ctx.jobs.len(), 1);
+        ctx.jobs[0].signal(libc::SIGKILL)?;
+        ctx.jobs[0].wait()?;
+        Ok(())
+    }
+
+    #[test]
+    fn background_functions() -> Result<()> {
+        let mut ctx = parser::vars::Context::new();
+        ctx.native_func = get_native_functions();
+        let err = parser::exec(&mut "sleep 0 | pwd &\n".as_bytes(), &mut ctx).unwrap_err();
+        assert_eq!(err.to_string(), "pwd can't run in the background, only external commands can");
+        assert!(ctx.jobs.is_empty() && ctx.get_var("!").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn term_editing() {
+        let mut term = Term::new();
+        for letter in "echo héllo wörld".chars() {
+            term.insert(letter);
+        }
+        term.idx = term.word_start(|letter| letter.is_alphanumeric());
+        assert_eq!(term.idx, 11);
+        term.remove(term.word_start(|letter| !letter.is_whitespace()), term.idx);
+        assert_eq!(term.input, "echo wörld");
+        // "$: " and 10 letters wrap into a second row at width 8
+        assert_eq!(term.position(&Prompt::new(), term.len(), 8), (1, 5));
+        assert_eq!(term.position(&Prompt::new(), 5, 8), (1, 0));
+    }
+
+    #[test]
+    fn history_expansion() -> Result<()> {
+        let mut history = History::new();
+        history.add("echo one")?;
+        history.add("echo two")?;
+        history.add("echo one")?;
+        assert_eq!(history.entries.len(), 2);
+        assert_eq!(history.expand("!! && !1")?, "echo one && echo two");
+        assert_eq!(history.expand("echo '!!' !-2 ! test != x")?, "echo '!!' echo two ! test != x");
+        assert!(history.expand("!5").is_err());
+        assert_eq!(history.search("two", 2), Some(0));
+        Ok(())
+    }
+
+    #[test]
+    fn history_limit() -> Result<()> {
+        let mut ctx = parser::vars::Context::new();
+        ctx.native_func = get_native_functions();
+        parser::exec(&mut "let HISTSIZE = 2\n".as_bytes(), &mut ctx)?;
+        let mut history = History::new();
+        history.limit = history::limit(&mut ctx);
+        for command in ["echo one", "echo two", "echo three"] {
+            history.add(command)?;
+        }
+        assert_eq!(history.entries.iter().map(|entry| entry.command.as_str()).collect::<Vec<_>>(), vec!["echo two", "echo three"]);
+        Ok(())
+    }
+
+    #[test]
+    fn history_sessions() -> Result<()> {
+        let dir = TempDir::new("sessions");
+        let path = dir.0.join("history");
+        let mut first = History::load(path.clone(), 1000)?;
+        first.add("echo first")?;
+        first.add("echo shared")?;
+        let mut second = History::load(path.clone(), 1000)?;
+        second.add("echo second")?;
+        first.delete(1)?;
+        let commands = |history: History| history.entries.into_iter().map(|entry| entry.command).collect::<Vec<_>>();
+        assert_eq!(commands(History::load(path.clone(), 1000)?), vec!["echo first", "echo second"]);
+        second.clear()?;
+        first.add("echo after clear")?;
+        assert_eq!(commands(History::load(path.clone(), 1000)?), vec!["echo after clear"]);
+        Ok(())
+    }
+
+    #[test]
+    fn history_suggestions() -> Result<()> {
+        let dir = TempDir::new("history");
+        let path = dir.0.join("history");
+        std::fs::write(&path, "1;git status\n2,3,/src;git stash\n3;git status\n4,1,/tmp\\;x;git show\n")?;
+        let history = History::load(path.clone(), 1000)?;
+        assert_eq!(history.entries.iter().map(|entry| entry.count).collect::<Vec<_>>(), vec![3, 2, 1]);
+        assert_eq!(history.suggest("git s", None), Some("git stash"));
+        assert_eq!(history.suggest("git s", Some("/tmp;x")), Some("git show"));
+        assert_eq!(history.suggest("git stat", Some("/src")), Some("git status"));
+        assert_eq!(history.suggest("git status", None), None);
+
+        let mut term = Term::new();
+        term.set_input("git");
+        term.suggestion = String::from(" status --short");
+        term.accept_suggestion(true);
+        assert_eq!((term.input.as_str(), term.suggestion.as_str()), ("git status", " --short"));
+        term.accept_suggestion(false);
+        assert_eq!((term.input.as_str(), term.idx), ("git status --short", 18));
+        Ok(())
+    }
+
+    #[test]
+    fn completion() -> Result<()> {
+        let tmp = TempDir::new("completion");
+        std::fs::create_dir_all(tmp.0.join("parser"))?;
+        for file in ["glob.rush", "glob.txt", "simple.rush", "script.sh"] {
+            std::fs::write(tmp.0.join(file), "")?;
+        }
+        let dir = tmp.0.to_string_lossy().to_string();
+        let mut shell = Shell::new();
+        shell.ctx.native_func = get_native_functions();
+        shell.ctx.set_var(String::from("env::RUSH_TEST"), Variable::String(String::from("1")));
+        let mut complete_at_end = |input: String| complete(&mut shell.ctx, &input, input.chars().count());
+        let values = |found: Completion| found.candidates.into_iter().map(|c| c.value).collect::<Vec<_>>();
+        let found = complete_at_end(format!("cat {}/gl", dir));
+        assert_eq!(found.start, 4);
+        assert_eq!(values(found), vec![format!("{}/glob.rush", dir), format!("{}/glob.txt", dir)]);
+        let found = complete_at_end(format!("cat \"{}/s", dir));
+        assert_eq!(values(found), vec![format!("\"{}/script.sh\"", dir), format!("\"{}/simple.rush\"", dir)]);
+        assert_eq!(common_prefix(&complete_at_end(format!("ls {}/pa", dir)).candidates), format!("{}/parser/", dir));
+        assert!(values(complete_at_end(String::from("ech"))).contains(&String::from("echo")));
+        assert_eq!(values(complete_at_end(String::from("echo ${env::RUSH_"))), vec!["${env::RUSH_TEST}"]);
+
+        let script = "function targets
+                echo $argv[1]-target
+                echo other-target
+            end
+            complete tool -a 'build check' -n
+            complete tool -s build -o --release -d 'Optimized build'
+            complete tool -s check -p '*.rush' -f $targets()
+            complete other -f targets";
+        parser::exec(&mut script.as_bytes(), &mut shell.ctx)?;
+        let mut complete_at_end = |input: String| complete(&mut shell.ctx, &input, input.chars().count());
+        assert_eq!(values(complete_at_end(String::from("tool "))), vec!["build", "check"]);
+        assert_eq!(values(complete_at_end(String::from("tool b"))), vec!["build"]);
+        let found = complete_at_end(String::from("tool build --r"));
+        assert_eq!(found.candidates[0].value, "--release");
+        assert_eq!(found.candidates[0].description.as_deref(), Some("Optimized build"));
+        assert_eq!(values(complete_at_end(format!("tool check {}/gl", dir))), vec![format!("{}/glob.rush", dir)]);
+        assert_eq!(values(complete_at_end(String::from("tool check ")))[..2], ["check-target", "other-target"]);
+        assert_eq!(values(complete_at_end(String::from("other ")))[..2], ["-target", "other-target"]);
+        assert!(values(complete_at_end(format!("tool check {}/", dir))).contains(&format!("{}/parser/", dir)));
+        assert_eq!(values(complete_at_end(format!("cd {}/", dir))), vec![format!("{}/parser/", dir)]);
+        assert!(parser::exec(&mut "complete tool -f $targets(x)".as_bytes(), &mut shell.ctx).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn highlighting() {
+        let mut shell = Shell::new();
+        shell.ctx.native_func = get_native_functions();
+        let mut highlighter = Highlighter::default();
+        highlighter.refresh(&shell.ctx);
+        assert_eq!(highlighter.highlight("if true; echo \"a $b\" 2> f # done"),
+            "\x1b[38;5;5mif\x1b[39m true\x1b[38;5;4m;\x1b[39m echo \x1b[38;5;3m\"a \x1b[38;5;6m$b\x1b[38;5;3m\"\x1b[39m \x1b[38;5;2m2>\x1b[39m f \x1b[38;5;8m# done\x1b[39m");
+        assert_eq!(highlighter.highlight("let x = ${#y} && cat 'q'"),
+            "\x1b[38;5;5mlet\x1b[39m x \x1b[38;5;4m=\x1b[39m \x1b[38;5;6m${#y}\x1b[39m \x1b[38;5;4m&&\x1b[39m cat \x1b[38;5;3m'q'\x1b[39m");
+        // unknown commands are only red once they were looked up in the background
+        assert_eq!(highlighter.highlight("rush_missing_command"), "rush_missing_command");
+        // the editor is woken to draw the input again whenever a lookup finished
+        let mut wake = highlighter.wake().unwrap();
+        while highlighter.lookup("rush_missing_command").is_none() {
+            assert_eq!(wake.read(&mut [0]).unwrap(), 1);
+        }
+        assert_eq!(highlighter.highlight("rush_missing_command"), "\x1b[38;5;1mrush_missing_command\x1b[39m");
+    }
+
+    #[test]
+    fn prompt_rendering() -> Result<()> {
+        let mut shell = Shell::new();
+        shell.ctx.native_func = get_native_functions();
+        let script = "function branch\n    echo main\nend\nlet PS1 = '\\\\e[32mrush\\\\e[0m (\\\\(branch))\\\\n\\\\$ '\nlet RPS1 = '[\\\\?]'\nfalse\n";
+        parser::exec(&mut script.as_bytes(), &mut shell.ctx)?;
+        let prompt = Prompt::render(&mut shell.ctx)?;
+        let dollar = if unsafe { libc::geteuid() } == 0 { "#" } else { "$" };
+        assert_eq!(prompt.left, format!("\x1b[32mrush\x1b[0m (main)\n{} ", dollar));
+        assert_eq!((prompt.right.as_str(), prompt.continuation.as_str()), ("[1]", "> "));
+        // the exit code isn't changed by the commands in the prompt
+        assert_eq!(shell.ctx.get_last_exit_code(), Some(1));
+        assert_eq!(crate::prompt::width("\x1b[32mrush\x1b[0m\x1b]0;title\x07 "), 5);
+        assert_eq!(prompt.end(8), (2, 2));
+
+        let mut term = Term::new();
+        term.set_input("ab\\\ncd");
+        assert_eq!(term.position(&prompt, term.len(), 8), (3, 4));
+        parser::exec(&mut "function rush_prompt\n    echo -n 'custom> '\nend\n".as_bytes(), &mut shell.ctx)?;
+        assert_eq!(Prompt::render(&mut shell.ctx)?.left, "custom> ");
+        // a failing prompt function falls back to the default prompt, and the shell keeps working
+        parser::exec(&mut "function rush_prompt\n    echo ${nope:?unset}\nend\n".as_bytes(), &mut shell.ctx)?;
+        assert!(Prompt::render(&mut shell.ctx).is_err());
+        shell.render_prompt();
+        assert_eq!(shell.prompt.left, Prompt::new().left);
+        assert_eq!(shell.ctx.scopes.len(), 1);
+        parser::exec(&mut "let still = $(echo running)".as_bytes(), &mut shell.ctx)?;
+        Ok(())
+    }
 }
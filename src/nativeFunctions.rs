@@ -1,6 +1,9 @@
 use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use crate::completion::CompletionSpec;
 use crate::parser::vars::{Context, NativeFunction, Variable, variables_to_string};
-use anyhow::{Result, bail};
+use anyhow::{Result, bail, Context as AnyhowContext};
 
 pub fn get_native_functions() -> HashMap<String, NativeFunction> {
     let mut map = HashMap::new();
@@ -18,44 +21,33 @@ pub fn get_native_functions() -> HashMap<String, NativeFunction> {
     });
 
     fn rush_test(_ctx: &mut Context, args: Vec<Variable>) -> Result<Variable> {
-        if args.len() != 3 {
-            bail!("Expected 3 arguments (source, operand, target), got {}", args.len());
-        }
-        let source = args.get(0).unwrap();
-        let operand = args.get(1).unwrap();
-        let target = args.get(2).unwrap();
-
-        let res = match operand {
-            Variable::String(operand) => {
-                match operand.as_str() {
-                    "=" => {
-                        if source.to_string() == target.to_string() {
-                            0
-                        } else {
-                            1
-                        }
-                    }
-                    "!=" => {
-                        if source.to_string() != target.to_string() {
-                            0
-                        } else {
-                            1
-                        }
-                    }
-                    _ => bail!("Unsupported operand: {}", operand)
-                }
-            }
-            _ => bail!("Unsupported operand: {}", operand)
-        };
-        Ok(Variable::I32(res))
+        let mut i = 0;
+        let res = test_or(&args, &mut i)?;
+        if let Some(arg) = args.get(i) {
+            bail!("test: unexpected argument {}", arg);
+        }
+        Ok(Variable::I32(if res { 0 } else { 1 }))
     }
     map.insert("test".to_string(), NativeFunction {
         name: "test".to_string(),
-        description: "Compares values. Supported operands are = != > < >= <=".to_string(),
-        args: vec![String::from("source"), String::from("operand"), String::from("target")],
+        description: "Evaluates a condition. Supports the comparisons = != < > <= >= -eq -ne -lt -le -gt -ge, the file tests -e -f -d -r -w -x -s -L, the string tests -z -n, negation with ! and combining with -a and -o".to_string(),
+        args: vec![String::from("expression")],
         func: rush_test
     });
 
+    fn rush_bracket_test(ctx: &mut Context, mut args: Vec<Variable>) -> Result<Variable> {
+        match args.pop() {
+            Some(Variable::String(str)) if str == "]" => rush_test(ctx, args),
+            _ => bail!("[: missing closing ]")
+        }
+    }
+    map.insert("[".to_string(), NativeFunction {
+        name: "[".to_string(),
+        description: "Same as test, but the expression has to be ended with ]".to_string(),
+        args: vec![String::from("expression"), String::from("]")],
+        func: rush_bracket_test
+    });
+
     fn rush_true(_ctx: &mut Context, _args: Vec<Variable>) -> Result<Variable> {
         Ok(Variable::I32(0))
     }
@@ -76,6 +68,7 @@ pub fn get_native_functions() -> HashMap<String, NativeFunction> {
         func: rush_false
     });
 
+    #[allow(clippy::get_first)]
     fn rush_export(ctx: &mut Context, args: Vec<Variable>) -> Result<Variable> {
         if args.len() != 1 && args.len() != 3 {
             bail!("Expected 1 (name) or 3 (name = value) arguments, got {}", args.len());
@@ -85,14 +78,15 @@ pub fn get_native_functions() -> HashMap<String, NativeFunction> {
             let value = ctx.get_var(&name.to_string());
             match value {
                 Some(value) => {
-                    let val = value.clone();
-                    ctx.exports.insert(name.to_string(), val);
+                    let val = value.to_string();
+                    set_export(ctx, &name.to_string(), val);
                 }
                 None => return Ok(Variable::I32(1))
             }
         } else {
             let value = args.get(2).unwrap();
             ctx.set_var(name.to_string(), value.clone());
+            set_export(ctx, &name.to_string(), value.to_string());
         }
         Ok(Variable::I32(0))
     }
@@ -103,6 +97,7 @@ pub fn get_native_functions() -> HashMap<String, NativeFunction> {
         func: rush_export
     });
 
+    #[allow(clippy::get_first)]
     fn rush_typeof(_ctx: &mut Context, args: Vec<Variable>) -> Result<Variable> {
         if args.len() != 1 {
             bail!("Expected 1 argument, got {}", args.len());
@@ -131,6 +126,7 @@ pub fn get_native_functions() -> HashMap<String, NativeFunction> {
         func: rush_typeof
     });
 
+    #[allow(clippy::get_first)]
     fn rush_length(_ctx: &mut Context, args: Vec<Variable>) -> Result<Variable> {
         if args.len() != 1 {
             return Ok(Variable::I64(args.len() as i64));
@@ -158,5 +154,510 @@ pub fn get_native_functions() -> HashMap<String, NativeFunction> {
         func: rush_length
     });
 
+    /// Finds the index of the job given by a job spec (%n, %% or a process id), defaulting to the most recent job
+    fn get_job_index(ctx: &Context, args: &[Variable]) -> Result<usize> {
+        if ctx.jobs.is_empty() {
+            bail!("No current job");
+        }
+        let spec = match args.first() {
+            Some(spec) => spec.to_string(),
+            None => return Ok(ctx.jobs.len() - 1)
+        };
+        let index = match spec.as_str() {
+            "%%" | "%+" => Some(ctx.jobs.len() - 1),
+            spec => match spec.strip_prefix('%') {
+                Some(id) => {
+                    let id: usize = id.parse().with_context(|| format!("Invalid job spec {}", spec))?;
+                    ctx.jobs.iter().position(|job| job.id == id)
+                }
+                None => {
+                    let pid: u32 = spec.parse().with_context(|| format!("Invalid job spec {}", spec))?;
+                    ctx.jobs.iter().position(|job| job.children.iter().any(|child| child.id() == pid))
+                }
+            }
+        };
+        match index {
+            Some(index) => Ok(index),
+            None => bail!("No such job {}", spec)
+        }
+    }
+
+    fn rush_jobs(ctx: &mut Context, _args: Vec<Variable>) -> Result<Variable> {
+        let mut stdout = ctx.get_stdout()?;
+        let mut finished = Vec::new();
+        for job in &mut ctx.jobs {
+            let code = job.try_wait()?;
+            if code.is_some() {
+                finished.push(job.id);
+            }
+            writeln!(stdout, "{}", job.describe(code))?;
+        }
+        ctx.jobs.retain(|job| !finished.contains(&job.id));
+        Ok(Variable::I32(0))
+    }
+    map.insert("jobs".to_string(), NativeFunction {
+        name: "jobs".to_string(),
+        description: "Lists background jobs".to_string(),
+        args: vec![],
+        func: rush_jobs
+    });
+
+    fn rush_fg(ctx: &mut Context, args: Vec<Variable>) -> Result<Variable> {
+        let index = get_job_index(ctx, &args)?;
+        let mut job = ctx.jobs.remove(index);
+        writeln!(ctx.get_stdout()?, "{}", job.command)?;
+        job.signal(libc::SIGCONT)?;
+        Ok(Variable::I32(job.wait()?))
+    }
+    map.insert("fg".to_string(), NativeFunction {
+        name: "fg".to_string(),
+        description: "Moves a job to the foreground and waits for it to finish".to_string(),
+        args: vec![String::from("job")],
+        func: rush_fg
+    });
+
+    fn rush_bg(ctx: &mut Context, args: Vec<Variable>) -> Result<Variable> {
+        let index = get_job_index(ctx, &args)?;
+        let job = ctx.jobs.get(index).unwrap();
+        job.signal(libc::SIGCONT)?;
+        writeln!(ctx.get_stdout()?, "[{}] {} &", job.id, job.command)?;
+        Ok(Variable::I32(0))
+    }
+    map.insert("bg".to_string(), NativeFunction {
+        name: "bg".to_string(),
+        description: "Resumes a stopped job in the background".to_string(),
+        args: vec![String::from("job")],
+        func: rush_bg
+    });
+
+    fn rush_wait(ctx: &mut Context, args: Vec<Variable>) -> Result<Variable> {
+        if args.is_empty() {
+            let mut code = 0;
+            for mut job in std::mem::take(&mut ctx.jobs) {
+                code = job.wait()?;
+            }
+            return Ok(Variable::I32(code));
+        }
+        let mut code = 0;
+        for arg in args {
+            let index = get_job_index(ctx, &[arg])?;
+            code = ctx.jobs.remove(index).wait()?;
+        }
+        Ok(Variable::I32(code))
+    }
+    map.insert("wait".to_string(), NativeFunction {
+        name: "wait".to_string(),
+        description: "Waits for the given jobs, or all jobs when none are given, to finish".to_string(),
+        args: vec![String::from("jobs")],
+        func: rush_wait
+    });
+
+    fn get_home(ctx: &Context) -> Result<PathBuf> {
+        match ctx.exports.get("HOME") {
+            Some(home) => Ok(PathBuf::from(home.to_string())),
+            None => Ok(PathBuf::from(std::env::var("HOME").with_context(|| "HOME not set")?))
+        }
+    }
+
+    /// Logical working directory, as tracked in PWD
+    fn get_pwd(ctx: &Context) -> Result<PathBuf> {
+        let current = std::env::current_dir()?;
+        match get_export(ctx, "PWD") {
+            Some(pwd) if Path::new(&pwd).canonicalize().ok() == current.canonicalize().ok() => Ok(PathBuf::from(pwd)),
+            _ => Ok(current)
+        }
+    }
+
+    /// Resolves . and .. without following symlinks
+    fn normalize_path(path: &Path) -> PathBuf {
+        let mut out = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::CurDir => {},
+                Component::ParentDir => { out.pop(); },
+                component => out.push(component)
+            }
+        }
+        out
+    }
+
+    /// Sets an exported variable, which spawned commands get in their environment
+    fn set_export(ctx: &mut Context, key: &str, value: String) {
+        ctx.exports.insert(key.to_string(), Variable::String(value));
+    }
+
+    /// Gets an exported variable, falling back to the environment the shell was started with
+    fn get_export(ctx: &Context, key: &str) -> Option<String> {
+        match ctx.exports.get(key) {
+            Some(value) => Some(value.to_string()),
+            None => std::env::var(key).ok()
+        }
+    }
+
+    /// Changes the working directory, keeping PWD and OLDPWD in sync
+    fn change_dir(ctx: &mut Context, dir: &Path, physical: bool) -> Result<PathBuf> {
+        let old = get_pwd(ctx)?;
+        let target = if physical {
+            old.join(dir).canonicalize().with_context(|| format!("cd: {}: No such file or directory", dir.display()))?
+        } else {
+            normalize_path(&old.join(dir))
+        };
+        std::env::set_current_dir(&target).with_context(|| format!("cd: {}: No such file or directory", dir.display()))?;
+        set_export(ctx, "OLDPWD", old.to_string_lossy().to_string());
+        set_export(ctx, "PWD", target.to_string_lossy().to_string());
+        Ok(target)
+    }
+
+    fn expand_home(ctx: &Context, dir: &str) -> Result<PathBuf> {
+        if dir == "~" {
+            return get_home(ctx);
+        }
+        match dir.strip_prefix("~/") {
+            Some(rest) => Ok(get_home(ctx)?.join(rest)),
+            None => Ok(PathBuf::from(dir))
+        }
+    }
+
+    /// Formats a directory for listing, replacing the home directory with ~
+    fn format_dir(ctx: &Context, dir: &Path) -> String {
+        if let Ok(home) = get_home(ctx) {
+            if let Ok(rest) = dir.strip_prefix(&home) {
+                return if rest.as_os_str().is_empty() { String::from("~") } else { format!("~/{}", rest.display()) };
+            }
+        }
+        dir.display().to_string()
+    }
+
+    fn print_dirs(ctx: &mut Context) -> Result<()> {
+        let mut dirs = vec![format_dir(ctx, &get_pwd(ctx)?)];
+        for dir in ctx.dir_stack.clone().iter().rev() {
+            dirs.push(format_dir(ctx, dir));
+        }
+        writeln!(ctx.get_stdout()?, "{}", dirs.join(" "))?;
+        Ok(())
+    }
+
+    fn rush_cd(ctx: &mut Context, args: Vec<Variable>) -> Result<Variable> {
+        let mut physical = false;
+        let mut dir = None;
+        for arg in args {
+            match arg.to_string().as_str() {
+                "-P" => physical = true,
+                "-L" => physical = false,
+                arg => if dir.is_none() { dir = Some(arg.to_string()) } else { bail!("cd: too many arguments") }
+            }
+        }
+        let target = match dir.as_deref() {
+            None => get_home(ctx)?,
+            Some("-") => {
+                let old = get_export(ctx, "OLDPWD").with_context(|| "cd: OLDPWD not set")?;
+                let target = change_dir(ctx, Path::new(&old), physical)?;
+                writeln!(ctx.get_stdout()?, "{}", target.display())?;
+                return Ok(Variable::I32(0));
+            },
+            Some(dir) => expand_home(ctx, dir)?
+        };
+        let is_relative = !target.is_absolute() && !target.starts_with(".") && !target.starts_with("..");
+        if is_relative {
+            let cdpath = get_export(ctx, "CDPATH").unwrap_or_default();
+            for base in cdpath.split(':').filter(|base| !base.is_empty()) {
+                let candidate = expand_home(ctx, base)?.join(&target);
+                if candidate.is_dir() {
+                    let target = change_dir(ctx, &candidate, physical)?;
+                    writeln!(ctx.get_stdout()?, "{}", target.display())?;
+                    return Ok(Variable::I32(0));
+                }
+            }
+        }
+        change_dir(ctx, &target, physical)?;
+        Ok(Variable::I32(0))
+    }
+    map.insert("cd".to_string(), NativeFunction {
+        name: "cd".to_string(),
+        description: "Changes the working directory. Supports -, ~ and CDPATH, -P resolves symlinks".to_string(),
+        args: vec![String::from("dir")],
+        func: rush_cd
+    });
+
+    fn rush_pwd(ctx: &mut Context, args: Vec<Variable>) -> Result<Variable> {
+        let physical = match args.first().map(|arg| arg.to_string()).as_deref() {
+            None | Some("-L") => false,
+            Some("-P") => true,
+            Some(arg) => bail!("pwd: invalid option {}", arg)
+        };
+        let dir = if physical { std::env::current_dir()?.canonicalize()? } else { get_pwd(ctx)? };
+        writeln!(ctx.get_stdout()?, "{}", dir.display())?;
+        Ok(Variable::I32(0))
+    }
+    map.insert("pwd".to_string(), NativeFunction {
+        name: "pwd".to_string(),
+        description: "Prints the working directory. -L (default) keeps symlinks, -P resolves them".to_string(),
+        args: vec![String::from("mode")],
+        func: rush_pwd
+    });
+
+    fn rush_pushd(ctx: &mut Context, args: Vec<Variable>) -> Result<Variable> {
+        let current = get_pwd(ctx)?;
+        let target = match args.first() {
+            Some(dir) => expand_home(ctx, &dir.to_string())?,
+            None => ctx.dir_stack.pop().with_context(|| "pushd: no other directory")?
+        };
+        change_dir(ctx, &target, false)?;
+        ctx.dir_stack.push(current);
+        print_dirs(ctx)?;
+        Ok(Variable::I32(0))
+    }
+    map.insert("pushd".to_string(), NativeFunction {
+        name: "pushd".to_string(),
+        description: "Pushes the working directory onto the directory stack and changes to dir, or swaps the top two directories".to_string(),
+        args: vec![String::from("dir")],
+        func: rush_pushd
+    });
+
+    fn rush_popd(ctx: &mut Context, _args: Vec<Variable>) -> Result<Variable> {
+        let target = ctx.dir_stack.last().with_context(|| "popd: directory stack empty")?.clone();
+        change_dir(ctx, &target, false)?;
+        ctx.dir_stack.pop();
+        print_dirs(ctx)?;
+        Ok(Variable::I32(0))
+    }
+    map.insert("popd".to_string(), NativeFunction {
+        name: "popd".to_string(),
+        description: "Removes the top directory from the directory stack and changes to it".to_string(),
+        args: vec![],
+        func: rush_popd
+    });
+
+    fn rush_dirs(ctx: &mut Context, args: Vec<Variable>) -> Result<Variable> {
+        match args.first().map(|arg| arg.to_string()).as_deref() {
+            None => print_dirs(ctx)?,
+            Some("-c") => ctx.dir_stack.clear(),
+            Some(arg) => bail!("dirs: invalid option {}", arg)
+        }
+        Ok(Variable::I32(0))
+    }
+    map.insert("dirs".to_string(), NativeFunction {
+        name: "dirs".to_string(),
+        description: "Prints the directory stack, -c clears it".to_string(),
+        args: vec![String::from("mode")],
+        func: rush_dirs
+    });
+
+    fn rush_set(ctx: &mut Context, args: Vec<Variable>) -> Result<Variable> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        match args.iter().map(|arg| arg.as_str()).collect::<Vec<&str>>().as_slice() {
+            [] | ["-o"] => writeln!(ctx.get_stdout()?, "pipefail\t{}", if ctx.pipefail { "on" } else { "off" })?,
+            [mode @ ("-o" | "+o"), "pipefail"] => ctx.pipefail = *mode == "-o",
+            [_, option] => bail!("set: invalid option name {}", option),
+            _ => bail!("set: usage: set [-o|+o] option")
+        }
+        Ok(Variable::I32(0))
+    }
+    map.insert("set".to_string(), NativeFunction {
+        name: "set".to_string(),
+        description: "Enables (-o) or disables (+o) a shell option, or lists them. Options: pipefail".to_string(),
+        args: vec![String::from("mode"), String::from("option")],
+        func: rush_set
+    });
+
+    fn rush_history(ctx: &mut Context, args: Vec<Variable>) -> Result<Variable> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let count = match args.iter().map(|arg| arg.as_str()).collect::<Vec<&str>>().as_slice() {
+            [] => ctx.history.entries.len(),
+            ["-c"] => {
+                ctx.history.clear()?;
+                return Ok(Variable::I32(0));
+            },
+            ["-d", num] => {
+                let num: i64 = num.parse().with_context(|| format!("history: {}: numeric argument required", num))?;
+                if ctx.history.get(num).is_none() { bail!("history: {}: position out of range", num) }
+                let idx = if num < 0 { ctx.history.entries.len() as i64 + num } else { num - 1 };
+                ctx.history.delete(idx as usize)?;
+                return Ok(Variable::I32(0));
+            },
+            [count] => count.parse().with_context(|| format!("history: {}: numeric argument required", count))?,
+            _ => bail!("history: usage: history [-c] [-d offset] [n]")
+        };
+        let start = ctx.history.entries.len().saturating_sub(count);
+        let lines: Vec<String> = ctx.history.entries.iter().enumerate().skip(start)
+            .map(|(i, entry)| format!("{:5}  {}", i + 1, entry.command))
+            .collect();
+        let mut stdout = ctx.get_stdout()?;
+        for line in lines {
+            writeln!(stdout, "{}", line)?;
+        }
+        Ok(Variable::I32(0))
+    }
+    map.insert("history".to_string(), NativeFunction {
+        name: "history".to_string(),
+        description: "Lists the last n history entries (all by default), -c clears the history and -d deletes an entry".to_string(),
+        args: vec![String::from("n")],
+        func: rush_history
+    });
+
+    fn rush_complete(ctx: &mut Context, args: Vec<Variable>) -> Result<Variable> {
+        let mut args = args.into_iter();
+        let command = match args.next() {
+            Some(command) => command.to_string(),
+            None => {
+                let mut commands: Vec<&String> = ctx.completions.keys().collect();
+                commands.sort();
+                let lines: Vec<String> = commands.into_iter()
+                    .flat_map(|command| ctx.completions[command].iter().map(|spec| spec.to_command(command)))
+                    .collect();
+                let mut stdout = ctx.get_stdout()?;
+                for line in lines {
+                    writeln!(stdout, "{}", line)?;
+                }
+                return Ok(Variable::I32(0));
+            }
+        };
+        if command == "-r" {
+            let command = args.next().with_context(|| "complete: -r needs a command")?.to_string();
+            ctx.completions.remove(&command);
+            return Ok(Variable::I32(0));
+        }
+        let mut spec = CompletionSpec::default();
+        while let Some(option) = args.next() {
+            let option = option.to_string();
+            let mut value = || args.next().with_context(|| format!("complete: {} needs a value", option));
+            match option.as_str() {
+                "-s" => spec.subcommand = Some(value()?.to_string()),
+                "-a" => match value()? {
+                    Variable::Array(words) => spec.words.extend(words.iter().map(|word| word.to_string())),
+                    words => spec.words.extend(words.to_string().split_whitespace().map(String::from))
+                },
+                "-o" => spec.flags.push(value()?.to_string()),
+                "-d" => spec.description = Some(value()?.to_string()),
+                "-f" => {
+                    let name = value()?.to_string();
+                    if ctx.get_func(&name).is_none() {
+                        bail!("complete: -f {}: not a function, pass one like -f my_completions or -f $my_completions()", name);
+                    }
+                    spec.function = Some(name);
+                },
+                "-p" => spec.patterns.push(value()?.to_string()),
+                "-n" => spec.no_files = true,
+                _ => bail!("complete: invalid option {}", option)
+            }
+        }
+        ctx.completions.entry(command).or_default().push(spec);
+        Ok(Variable::I32(0))
+    }
+    map.insert("complete".to_string(), NativeFunction {
+        name: "complete".to_string(),
+        description: "Declares completions for the arguments of a command (after subcommand -s): words -a, flags -o with description -d, \
+            function -f printing candidates, file patterns -p, no files -n. Lists them without arguments, -r removes them".to_string(),
+        args: vec![String::from("command"), String::from("options")],
+        func: rush_complete
+    });
+
     map
-}
\ No newline at end of file
+}
+
+fn is_test_operator(arg: Option<&Variable>, operators: &[&str]) -> bool {
+    matches!(arg, Some(Variable::String(str)) if operators.contains(&str.as_str()))
+}
+
+const TEST_BINARY_OPERATORS: [&str; 13] = ["=", "==", "!=", "<", ">", "<=", ">=", "-eq", "-ne", "-lt", "-le", "-gt", "-ge"];
+const TEST_UNARY_OPERATORS: [&str; 10] = ["-e", "-f", "-d", "-r", "-w", "-x", "-s", "-L", "-z", "-n"];
+
+fn test_or(args: &[Variable], i: &mut usize) -> Result<bool> {
+    let mut res = test_and(args, i)?;
+    while is_test_operator(args.get(*i), &["-o"]) {
+        *i += 1;
+        res = test_and(args, i)? || res;
+    }
+    Ok(res)
+}
+
+fn test_and(args: &[Variable], i: &mut usize) -> Result<bool> {
+    let mut res = test_not(args, i)?;
+    while is_test_operator(args.get(*i), &["-a"]) {
+        *i += 1;
+        res = test_not(args, i)? && res;
+    }
+    Ok(res)
+}
+
+fn test_not(args: &[Variable], i: &mut usize) -> Result<bool> {
+    // a lone ! is just a non-empty string
+    if is_test_operator(args.get(*i), &["!"]) && *i + 1 < args.len() {
+        *i += 1;
+        return Ok(!test_not(args, i)?);
+    }
+    test_primary(args, i)
+}
+
+fn test_primary(args: &[Variable], i: &mut usize) -> Result<bool> {
+    let arg = match args.get(*i) {
+        Some(arg) => arg,
+        None => return Ok(false)
+    };
+    if is_test_operator(args.get(*i + 1), &TEST_BINARY_OPERATORS) && *i + 2 < args.len() {
+        let operator = args[*i + 1].to_string();
+        let target = &args[*i + 2];
+        *i += 3;
+        return test_binary(arg, &operator, target);
+    }
+    if is_test_operator(Some(arg), &["("]) && *i + 1 < args.len() {
+        *i += 1;
+        let res = test_or(args, i)?;
+        if !is_test_operator(args.get(*i), &[")"]) {
+            bail!("test: expected )");
+        }
+        *i += 1;
+        return Ok(res);
+    }
+    if is_test_operator(Some(arg), &TEST_UNARY_OPERATORS) && *i + 1 < args.len() {
+        let operator = arg.to_string();
+        let target = args[*i + 1].to_string();
+        *i += 2;
+        return test_unary(&operator, &target);
+    }
+    *i += 1;
+    Ok(!arg.to_string().is_empty())
+}
+
+fn test_binary(source: &Variable, operator: &str, target: &Variable) -> Result<bool> {
+    let numeric = || crate::parser::math::compare(source, target)
+        .with_context(|| format!("test: {} {} {} needs numbers", source, operator, target));
+    // < > <= >= always compare strings, -lt -gt -le -ge compare numbers
+    let ordering = || source.to_string().cmp(&target.to_string());
+    Ok(match operator {
+        "=" | "==" => source.to_string() == target.to_string(),
+        "!=" => source.to_string() != target.to_string(),
+        "<" => ordering().is_lt(),
+        ">" => ordering().is_gt(),
+        "<=" => ordering().is_le(),
+        ">=" => ordering().is_ge(),
+        "-eq" => numeric()?.is_eq(),
+        "-ne" => numeric()?.is_ne(),
+        "-lt" => numeric()?.is_lt(),
+        "-le" => numeric()?.is_le(),
+        "-gt" => numeric()?.is_gt(),
+        "-ge" => numeric()?.is_ge(),
+        _ => bail!("test: unsupported operator {}", operator)
+    })
+}
+
+fn test_unary(operator: &str, target: &str) -> Result<bool> {
+    let path = Path::new(target);
+    let access = |mode: libc::c_int| match std::ffi::CString::new(target) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), mode) == 0 },
+        Err(_) => false
+    };
+    Ok(match operator {
+        "-e" => path.exists(),
+        "-f" => path.is_file(),
+        "-d" => path.is_dir(),
+        "-r" => access(libc::R_OK),
+        "-w" => access(libc::W_OK),
+        "-x" => access(libc::X_OK),
+        "-s" => path.metadata().map(|meta| meta.len() > 0).unwrap_or(false),
+        "-L" => path.symlink_metadata().map(|meta| meta.file_type().is_symlink()).unwrap_or(false),
+        "-z" => target.is_empty(),
+        "-n" => !target.is_empty(),
+        _ => bail!("test: unsupported operator {}", operator)
+    })
+}
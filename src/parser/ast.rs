@@ -1,13 +1,22 @@
-use crate::parser::tokens::{Token, Tokens};
+use crate::parser::glob;
+use crate::parser::tokens::{tokenize, Token, Tokens};
 use anyhow::{bail, Context, Result};
 
 #[derive(Debug, Clone)]
 pub struct LetExpression {
     pub key: Box<Value>,
+    /// let map[key] = value or let arr[+] = value
+    pub index: Vec<IndexValue>,
     pub vartype: Option<String>,
     pub value: Box<Value>
 }
 
+/// ! pipeline, inverts the exit code
+#[derive(Debug, Clone)]
+pub struct NotExpression {
+    pub expr: Box<Expression>
+}
+
 #[derive(Debug, Clone)]
 pub struct AndExpression {
     pub first: Box<Expression>,
@@ -37,7 +46,7 @@ pub struct WhileExpression {
 pub struct ForExpression {
     pub arg_value: Value,
     pub arg_key: Option<Value>,
-    pub list: Value,
+    pub list: ForValue,
     pub contents: Vec<Expression>,
     pub else_contents: Vec<Expression>
 }
@@ -45,7 +54,13 @@ pub struct ForExpression {
 #[derive(Debug, Clone)]
 pub enum ForValue {
     Value(Value),
-    Range(Option<u32>, Option<u32>)
+    /// numeric range (start..end, start..=end or start..end..step)
+    Range {
+        start: Value,
+        end: Value,
+        step: Option<Value>,
+        inclusive: bool
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -55,14 +70,173 @@ pub struct DefinedFunctionCall {
 }
 
 #[derive(Debug, Clone)]
+pub enum IndexValue {
+    Key(Value),
+    /// slice (start..end or start..=end), bounds default to the start and end of the array
+    Range {
+        start: Option<Value>,
+        end: Option<Value>,
+        inclusive: bool
+    },
+    /// [+], appends to an array in let expressions
+    Append
+}
+
+/// Operator in ${...}. Words are expanded like here-documents when used, patterns match like globs
+#[derive(Debug, Clone)]
+pub enum ParameterOperator {
+    /// ${name:-word}, word if name is unset or empty
+    Default(String),
+    /// ${name:=word}, also assigns word to name
+    Assign(String),
+    /// ${name:?word}, fails with word as the message
+    Error(String),
+    /// ${#name}
+    Length,
+    /// ${name#pattern}, ${name##pattern} removes the longest match
+    RemovePrefix(String, bool),
+    /// ${name%pattern}, ${name%%pattern} removes the longest match
+    RemoveSuffix(String, bool),
+    /// ${name/pattern/replacement}, ${name//pattern/replacement} replaces all matches
+    Replace { pattern: String, replacement: String, all: bool },
+    /// ${name^} uppercases the first letter, ${name^^} all letters
+    Upper(bool),
+    /// ${name,} lowercases the first letter, ${name,,} all letters
+    Lower(bool)
+}
+
+/// Parses the contents of ${...} with an operator, like name:-default
+pub fn parse_parameter(content: &str) -> Result<Value> {
+    if let Some(name) = content.strip_prefix('#') {
+        if name.is_empty() || !name.chars().all(|letter| letter.is_ascii_alphanumeric() || matches!(letter, '_' | ':')) {
+            bail!("Bad substitution ${{{}}}", content);
+        }
+        return Ok(Value::Parameter(name.to_string(), Box::new(ParameterOperator::Length)));
+    }
+    let mut split = content.len();
+    for (x, letter) in content.char_indices() {
+        let operator_colon = letter == ':' && matches!(content[x + 1..].chars().next(), Some('-' | '=' | '?'));
+        if operator_colon || !(letter.is_ascii_alphanumeric() || matches!(letter, '_' | ':')) {
+            split = x;
+            break;
+        }
+    }
+    let (name, rest) = content.split_at(split);
+    if name.is_empty() {
+        bail!("Bad substitution ${{{}}}", content);
+    }
+    let word = |prefix: &str| rest.strip_prefix(prefix).map(|word| word.to_string());
+    let operator = if let Some(word) = word(":-") {
+        ParameterOperator::Default(word)
+    } else if let Some(word) = word(":=") {
+        ParameterOperator::Assign(word)
+    } else if let Some(word) = word(":?") {
+        ParameterOperator::Error(word)
+    } else if let Some(pattern) = word("##") {
+        ParameterOperator::RemovePrefix(pattern, true)
+    } else if let Some(pattern) = word("#") {
+        ParameterOperator::RemovePrefix(pattern, false)
+    } else if let Some(pattern) = word("%%") {
+        ParameterOperator::RemoveSuffix(pattern, true)
+    } else if let Some(pattern) = word("%") {
+        ParameterOperator::RemoveSuffix(pattern, false)
+    } else if let Some(rest) = word("/") {
+        let (all, rest) = match rest.strip_prefix('/') {
+            Some(rest) => (true, rest.to_string()),
+            None => (false, rest)
+        };
+        let mut pattern = String::new();
+        let mut chars = rest.chars();
+        while let Some(letter) = chars.next() {
+            match letter {
+                '\\' => {
+                    pattern.push(letter);
+                    pattern.extend(chars.next());
+                },
+                '/' => break,
+                letter => pattern.push(letter)
+            }
+        }
+        ParameterOperator::Replace { pattern, replacement: chars.collect(), all }
+    } else if rest == "^^" || rest == "^" {
+        ParameterOperator::Upper(rest == "^^")
+    } else if rest == ",," || rest == "," {
+        ParameterOperator::Lower(rest == ",,")
+    } else {
+        bail!("Bad substitution ${{{}}}", content);
+    };
+    Ok(Value::Parameter(name.to_string(), Box::new(operator)))
+}
+
+/// Parses text like the contents of a double quoted string, with variables, ${...} and $((...)) expanded.
+/// Only \$ and \\ are escapes, other backslashes, quotes and @ are kept
+pub fn parse_quoted(str: &str) -> Result<Value> {
+    let mut quoted = String::from("\"");
+    let mut chars = str.chars().peekable();
+    // parentheses open in a $(...), its command is tokenized as usual
+    let mut open = 0;
+    while let Some(letter) = chars.next() {
+        match letter {
+            '\\' if matches!(chars.peek(), Some('$' | '\\')) => {
+                quoted.push(letter);
+                quoted.extend(chars.next());
+            },
+            '$' if chars.peek() == Some(&'(') => {
+                quoted.push(letter);
+                quoted.extend(chars.next());
+                open += 1;
+            },
+            '(' if open > 0 => {
+                quoted.push(letter);
+                open += 1;
+            },
+            ')' if open > 0 => {
+                quoted.push(letter);
+                open -= 1;
+            },
+            _ if open > 0 => quoted.push(letter),
+            '\\' | '"' | '@' => {
+                quoted.push('\\');
+                quoted.push(letter);
+            },
+            letter => quoted.push(letter)
+        }
+    }
+    quoted.push('"');
+    let tokens = tokenize(&mut quoted.as_bytes())?;
+    if tokens.is_empty() {
+        return Ok(Value::Literal(String::new()));
+    }
+    let end = tokens.len();
+    Tree { tokens, i: 0 }.get_value(end, false)
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum Value {
     Literal(String),
+    /// unquoted word part containing *, ?, [...] or {a,b}, expanded against the filesystem in command arguments
+    Glob(String),
     Variable(String),
+    /// ${name:-default} and other operators applied to a variable
+    Parameter(String, Box<ParameterOperator>),
+    /// ~ or ~user, the home directory
+    Tilde(String),
     ArrayVariable(String),
     ArrayDefinition(Vec<Value>),
+    /// [key=value ...], [=] is an empty map
+    MapDefinition(Vec<(Value, Value)>),
+    Index(Box<Value>, Box<IndexValue>),
     ValueFunction(DefinedFunctionCall),
+    Math(String),
     Expressions(Vec<Expression>),
-    Values(Vec<Value>)
+    /// @(...), the output split into an array on the characters in IFS (newline by default)
+    ArrayExpressions(Vec<Expression>),
+    /// <(...) or >(...) (write is true), expands to a /dev/fd path connected to the commands
+    ProcessSubstitution(Vec<Expression>, bool),
+    Values(Vec<Value>),
+    /// adjacent values forming a single word, like "a$b"
+    Concat(Vec<Value>)
 }
 
 #[derive(Debug, Clone)]
@@ -75,32 +249,55 @@ pub struct FunctionVariable {
 pub struct FunctionDefinitionExpression {
     pub name: String,
     pub description: Option<String>,
+    #[allow(dead_code)]
     pub on_event: Option<String>,
     pub args: Vec<FunctionVariable>,
     pub body: Box<Expression>
 }
 
+/// Commands connected with |, each reading the output of the one before it
 #[derive(Debug, Clone)]
-pub struct RedirectTargetExpression {
-    pub source: Box<Expression>,
-    pub target: Box<Expression>
+pub struct PipelineExpression {
+    pub commands: Vec<Expression>
+}
+
+#[derive(Debug, Clone)]
+pub enum RedirectMode {
+    Write,
+    Append,
+    /// writes to the same target as the given file descriptor
+    Duplicate(i32),
+    Close
 }
 
 #[derive(Debug, Clone)]
 pub struct FileTargetExpression {
     pub source: Option<Box<Expression>>,
-    pub target: Box<Value>
+    pub target: Box<Value>,
+    /// redirected file descriptor, 1 for stdout and 2 for stderr
+    pub fd: i32,
+    pub mode: RedirectMode
+}
+
+#[derive(Debug, Clone)]
+pub enum SourceMode {
+    File,
+    /// source is the body of the here-document, expand replaces variables in it
+    HereDoc { expand: bool },
+    HereString
 }
 
 #[derive(Debug, Clone)]
 pub struct FileSourceExpression {
     pub source: Box<Value>,
+    pub mode: SourceMode,
     pub target: Option<Box<Expression>>
 }
 
 #[derive(Debug, Clone)]
 pub enum CommandValue {
     Value(Value),
+    #[allow(dead_code)]
     Var(String, Value)
 }
 
@@ -109,6 +306,16 @@ pub struct BreakExpression {
     pub num: Box<Value>
 }
 
+#[derive(Debug, Clone)]
+pub struct ContinueExpression {
+    pub num: Box<Value>
+}
+
+#[derive(Debug, Clone)]
+pub struct ReturnExpression {
+    pub value: Box<Value>
+}
+
 #[derive(Debug, Clone)]
 pub enum Expression {
     LetExpression(LetExpression),
@@ -118,13 +325,16 @@ pub enum Expression {
     IfExpression(IfExpression),
     WhileExpression(WhileExpression),
     ForExpression(ForExpression),
-    RedirectTargetExpression(RedirectTargetExpression),
+    PipelineExpression(PipelineExpression),
     FileTargetExpression(FileTargetExpression),
     FileSourceExpression(FileSourceExpression),
     Expressions(Vec<Expression>),
     OrExpression(OrExpression),
     AndExpression(AndExpression),
-    BreakExpression(BreakExpression)
+    NotExpression(NotExpression),
+    BreakExpression(BreakExpression),
+    ContinueExpression(ContinueExpression),
+    ReturnExpression(ReturnExpression)
 }
 
 #[derive(Debug)]
@@ -144,7 +354,7 @@ impl Tree {
                     if buf.len() == 1 {
                         values.push(CommandValue::Value(buf.pop().unwrap()));
                     } else {
-                        values.push(CommandValue::Value(Value::Values(buf)));
+                        values.push(CommandValue::Value(Value::Concat(buf)));
                     }
                     buf = Vec::new();
                 }
@@ -154,19 +364,26 @@ impl Tree {
                 continue;
             }
             let val = match &token {
-                Tokens::Literal(str) => Value::Literal(str.clone()),
-                Tokens::SubStart => {
-                    let val = self.get_value(end, false)?;
-                    token = self.get_current_token();
-                    val
+                Tokens::Literal(str, false) if glob::is_pattern(str) => Value::Glob(str.clone()),
+                Tokens::Literal(str, _) => Value::Literal(str.clone()),
+                // brackets are separate tokens for arrays and indexes, but part of the pattern in arguments
+                Tokens::ArrayStart | Tokens::ArrayEnd => Value::Glob(token.to_str()),
+                Tokens::SubStart => Value::Expressions(self.parse_sub_expressions(end)?),
+                Tokens::ArraySubStart => Value::ArrayExpressions(self.parse_sub_expressions(end)?),
+                Tokens::ProcessSubStart { write } => {
+                    let write = *write;
+                    Value::ProcessSubstitution(self.parse_sub_expressions(end)?, write)
                 },
                 Tokens::StringVariable(str, _) => {
                     if str.is_empty() { bail!("Expected variable name"); }
-                    Value::Variable(str.clone())
+                    self.parse_indexes(Value::Variable(str.clone()), end)?
                 },
-                Tokens::ArrayVariable(str, _) => Value::ArrayVariable(str.clone()),
-                Tokens::FileWrite => break,
-                Tokens::FileRead => break,
+                Tokens::ArrayVariable(str, _) => self.parse_indexes(Value::ArrayVariable(str.clone()), end)?,
+                Tokens::Math(str) => Value::Math(str.clone()),
+                Tokens::ParameterExpansion(str) => parse_parameter(str)?,
+                Tokens::Tilde(user) => Value::Tilde(user.clone()),
+                Tokens::FileWrite { .. } | Tokens::FileWriteAll { .. } | Tokens::FileDuplicate { .. } => break,
+                Tokens::FileRead | Tokens::HereDoc { .. } | Tokens::HereString => break,
                 Tokens::RedirectInto => break,
                 Tokens::And => break,
                 Tokens::Or => break,
@@ -177,11 +394,7 @@ impl Tree {
                     }
                     Value::Literal(token.to_str())
                 }
-                Tokens::StringFunction(_) | Tokens::ArrayFunction(_) => {
-                    let val = self.get_value(end, false)?;
-                    token = self.get_current_token();
-                    val
-                }
+                Tokens::StringFunction(_) | Tokens::ArrayFunction(_) => self.get_value(end, false)?,
                 _ => {
                     Value::Literal(token.to_str())
                 }
@@ -192,16 +405,12 @@ impl Tree {
             token = &self.tokens.get(self.i).unwrap().token;
             if matches!(token, Tokens::CommandEnd(_)) { break }
         }
-        match &token {
-            Tokens::FileWrite | Tokens::FileRead | Tokens::RedirectInto => self.i -= 1,
-            _ => {}
-        }
         // self.next();
         if !buf.is_empty() {
             if buf.len() == 1 {
                 values.push(CommandValue::Value(buf.pop().unwrap()));
             } else {
-                values.push(CommandValue::Value(Value::Values(buf)));
+                values.push(CommandValue::Value(Value::Concat(buf)));
             }
         }
         Ok(Expression::Command(values))
@@ -210,64 +419,258 @@ impl Tree {
     fn parse_let(&mut self, end: usize) -> Result<Expression> {
         if end < self.i + 2 { bail!("Let needs name and equal sign (=) at minimum") }
         self.inc();
+        while self.i < end && matches!(self.get_current_token(), Tokens::Space) {
+            self.inc();
+        }
+        // let name: type = value
+        let typed = match self.tokens.get(self.i).map(|t| &t.token) {
+            Some(Tokens::Literal(str, _)) => split_type(str).map(|(name, vartype)| (name.to_string(), vartype.to_string())),
+            _ => None
+        };
+        if let Some((name, mut vartype)) = typed {
+            if vartype.is_empty() {
+                self.inc();
+                vartype = self.parse_literal(end).with_context(|| format!("Expected type for {}", name))?;
+            }
+            self.skip_to_export_set(end)?;
+            let value = Box::new(self.get_value(end, false)?);
+            return Ok(Expression::LetExpression(LetExpression { key: Box::new(Value::Literal(name)), index: Vec::new(), vartype: Some(vartype), value }));
+        }
         let mut len = 0;
         for token in &self.tokens[self.i..] {
             match token.token {
-                Tokens::ExportSet => { break },
+                Tokens::ExportSet | Tokens::ArrayStart => { break },
                 _ => len += 1
             }
         }
         let key = Box::new(self.get_value(self.i + len, false)?);
-        self.inc(); // ????
-        self.inc();
+        let mut index = Vec::new();
+        while self.i + 1 < end && matches!(self.tokens[self.i + 1].token, Tokens::ArrayStart) {
+            self.inc();
+            index.push(self.parse_index(end)?);
+        }
+        self.skip_to_export_set(end)?;
         let value = Box::new(self.get_value(end, false)?);
-        Ok(Expression::LetExpression(LetExpression { key, vartype: None, value }))
+        Ok(Expression::LetExpression(LetExpression { key, index, vartype: None, value }))
     }
 
-    fn parse_read(&mut self, target: Option<Expression>, _end: usize) -> Result<Expression> {
-        let target = target.map(Box::new);
-        self.i += 1;
+    /// Moves past the equal sign following the name of a let expression
+    fn skip_to_export_set(&mut self, end: usize) -> Result<()> {
+        loop {
+            self.inc();
+            if self.i >= end { bail!("Let needs name and equal sign (=) at minimum") }
+            match self.get_current_token() {
+                Tokens::ExportSet => break,
+                Tokens::Space => {},
+                token => bail!("Expected equal sign (=) in let, got {}", token.to_str())
+            }
+        }
+        self.inc();
+        Ok(())
+    }
+
+    /// Wraps a variable in indexes directly following it, like $map[key][0] or @arr[1..3]. Leaves the cursor on the
+    /// last closing bracket
+    fn parse_indexes(&mut self, mut value: Value, end: usize) -> Result<Value> {
+        while self.i + 1 < end && matches!(self.tokens[self.i + 1].token, Tokens::ArrayStart) {
+            self.inc();
+            let index = self.parse_index(end)?;
+            if matches!(index, IndexValue::Append) { bail!("[+] can only be used to append in let") }
+            value = Value::Index(Box::new(value), Box::new(index));
+        }
+        Ok(value)
+    }
+
+    /// Parses a single [key], [start..end] or [+], starting at the opening bracket
+    fn parse_index(&mut self, end: usize) -> Result<IndexValue> {
+        self.inc();
+        let mut parts: Vec<Vec<Value>> = vec![Vec::new()];
+        let mut inclusive = false;
+        loop {
+            if self.i >= end { bail!("Expected ] to end index") }
+            match self.get_current_token() {
+                Tokens::ArrayEnd => break,
+                Tokens::Space => {},
+                Tokens::Literal(str, _) => {
+                    for (x, segment) in str.split("..").enumerate() {
+                        if x > 0 { parts.push(Vec::new()); }
+                        if !segment.is_empty() {
+                            parts.last_mut().unwrap().push(Value::Literal(segment.to_string()));
+                        }
+                    }
+                },
+                // ..= is tokenized as a literal ending with .. followed by =
+                Tokens::ExportSet => {
+                    if parts.len() != 2 || !parts[1].is_empty() || inclusive { bail!("Unexpected = in index") }
+                    inclusive = true;
+                },
+                Tokens::StringVariable(name, _) => {
+                    let val = self.parse_indexes(Value::Variable(name.clone()), end)?;
+                    parts.last_mut().unwrap().push(val);
+                },
+                Tokens::Math(expr) => {
+                    let val = Value::Math(expr.clone());
+                    parts.last_mut().unwrap().push(val);
+                },
+                token => bail!("Unexpected {} in index", token.to_str())
+            }
+            self.inc();
+        }
+        let mut parts = parts.into_iter().map(|mut part| match part.len() {
+            0 => Ok(None),
+            1 => Ok(part.pop()),
+            _ => bail!("Index must be a single value")
+        });
+        let start = parts.next().unwrap()?;
+        let index = match parts.next() {
+            None => match start {
+                Some(Value::Literal(str)) if str == "+" => IndexValue::Append,
+                Some(key) => IndexValue::Key(key),
+                None => bail!("Empty index")
+            },
+            Some(end) => IndexValue::Range { start, end: end?, inclusive }
+        };
+        if parts.next().is_some() { bail!("Index ranges can't have a step") }
+        Ok(index)
+    }
+
+    /// Finds the end of the file name following a redirect, which may contain parenthesis like >(cmd)
+    fn get_redirect_value_end(&self) -> Result<usize> {
         let mut val_end = self.i;
         let mut found_first = false;
+        let mut lvl = 0;
         for token in &self.tokens[self.i..] {
             val_end += 1;
             match token.token {
+                Tokens::SubStart | Tokens::ArraySubStart | Tokens::ProcessSubStart { .. } | Tokens::StringFunction(_) | Tokens::ArrayFunction(_) | Tokens::ParenthesisStart => {
+                    found_first = true;
+                    lvl += 1;
+                },
+                Tokens::ParenthesisEnd => lvl -= 1,
+                _ if lvl > 0 => {},
                 Tokens::Space => if found_first { break },
                 Tokens::CommandEnd(_) => if !found_first { bail!("Unexpected command end") } else { break },
-                Tokens::FileRead => bail!("Unexpected file read (<)"),
-                Tokens::FileWrite => bail!("Unexpected file write (>)"),
+                Tokens::FileRead | Tokens::HereDoc { .. } | Tokens::HereString | Tokens::FileWrite { .. } | Tokens::FileWriteAll { .. } | Tokens::FileDuplicate { .. } => if found_first {
+                    break
+                } else {
+                    bail!("Unexpected redirect ({})", token.token.to_str())
+                },
                 _ => { found_first = true; }
             }
         }
-        val_end -= 1;
+        Ok(val_end - 1)
+    }
+
+    fn parse_read(&mut self, target: Option<Expression>, _end: usize) -> Result<Expression> {
+        let target = target.map(Box::new);
+        let mode = match self.get_current_token() {
+            Tokens::HereDoc { content, expand } => {
+                let source = Box::new(Value::Literal(content.clone()));
+                let mode = SourceMode::HereDoc { expand: *expand };
+                self.inc();
+                return Ok(Expression::FileSourceExpression(FileSourceExpression { source, mode, target }));
+            },
+            Tokens::HereString => SourceMode::HereString,
+            _ => SourceMode::File
+        };
+        self.i += 1;
+        let val_end = self.get_redirect_value_end()?;
         let source = Box::new(self.get_value(val_end, false)?);
         self.inc();
-        Ok(Expression::FileSourceExpression(FileSourceExpression { source, target }))
+        Ok(Expression::FileSourceExpression(FileSourceExpression { source, mode, target }))
     }
 
     fn parse_write(&mut self, source: Option<Expression>, _end: usize) -> Result<Expression> {
         let source = source.map(Box::new);
+        let (fd, mode, all) = match self.get_current_token() {
+            Tokens::FileWrite { fd, append } => (*fd, if *append { RedirectMode::Append } else { RedirectMode::Write }, false),
+            Tokens::FileWriteAll { append } => (1, if *append { RedirectMode::Append } else { RedirectMode::Write }, true),
+            Tokens::FileDuplicate { fd, target } => {
+                let mode = match target {
+                    Some(target) => RedirectMode::Duplicate(check_fd(*target)?),
+                    None => RedirectMode::Close
+                };
+                let fd = check_fd(*fd)?;
+                let target = Box::new(Value::Literal(self.get_current_token().to_str()));
+                self.inc();
+                return Ok(Expression::FileTargetExpression(FileTargetExpression { source, target, fd, mode }));
+            },
+            token => bail!("Expected file redirect, got {}", token.to_str())
+        };
+        check_fd(fd)?;
         self.i += 1;
-        let mut val_end = self.i;
-        let mut found_first = false;
-        for token in &self.tokens[self.i..] {
-            val_end += 1;
-            match token.token {
-                Tokens::Space => if found_first { break },
-                Tokens::CommandEnd(_) => if !found_first { bail!("Unexpected command end") } else { break },
-                Tokens::FileRead => bail!("Unexpected file read (<)"),
-                Tokens::FileWrite => bail!("Unexpected file write (>)"),
-                _ => { found_first = true; }
+        let val_end = self.get_redirect_value_end()?;
+        let target = Box::new(self.get_value(val_end, false)?);
+        self.inc();
+        let expr = Expression::FileTargetExpression(FileTargetExpression { source, target, fd, mode });
+        if !all {
+            return Ok(expr);
+        }
+        // &> file is > file 2>&1
+        Ok(Expression::FileTargetExpression(FileTargetExpression {
+            source: Some(Box::new(expr)),
+            target: Box::new(Value::Literal(String::from("2>&1"))),
+            fd: 2,
+            mode: RedirectMode::Duplicate(1)
+        }))
+    }
+
+    fn parse_function(&mut self, end: usize) -> Result<FunctionDefinitionExpression> {
+        self.inc();
+        let name = self.parse_literal(end).with_context(|| "Expected function name")?;
+        let mut description = None;
+        let mut on_event = None;
+        let mut args = Vec::new();
+        self.inc();
+        loop {
+            if self.i >= end { bail!("Unexpected end of function {} definition", name) }
+            match self.get_current_token() {
+                Tokens::CommandEnd(_) => break,
+                Tokens::Space => {},
+                Tokens::Literal(str, _) => match str.as_str() {
+                    "-d" | "--description" => {
+                        self.inc();
+                        description = Some(self.parse_literal(end).with_context(|| "Expected function description")?);
+                    },
+                    "-e" | "--on-event" => {
+                        self.inc();
+                        on_event = Some(self.parse_literal(end).with_context(|| "Expected event name")?);
+                    },
+                    _ => args.push(match split_type(str) {
+                        Some((name, vartype)) => FunctionVariable { name: name.to_string(), vartype: Some(vartype.to_string()) },
+                        None => FunctionVariable { name: str.clone(), vartype: None }
+                    })
+                },
+                token => bail!("Unexpected token {} in function {} definition", token.to_str(), name)
             }
+            self.inc();
+        }
+
+        let mut contents = Vec::new();
+        loop {
+            if self.i >= end { bail!("Expected END for function {}", name) }
+            match self.get_current_token() {
+                Tokens::End => break,
+                Tokens::Else => bail!("Unexpected ELSE in function {}", name),
+                Tokens::CommandEnd(_) => { self.inc(); },
+                Tokens::Space => { self.inc(); },
+                _ => contents.push(self.get_expression(end).with_context(|| format!("Error getting contents for function {}", name))?)
+            };
         }
-        val_end -= 1;
-        let target = Box::new(self.get_value(val_end, false)?);
         self.inc();
-        Ok(Expression::FileTargetExpression(FileTargetExpression { source, target }))
+        Ok(FunctionDefinitionExpression { name, description, on_event, args, body: Box::new(Expression::Expressions(contents)) })
     }
 
-    fn parse_function(&mut self, _end: usize) -> Result<FunctionDefinitionExpression> {
-        bail!("Functions not yet implemented")
+    /// Skips spaces and returns the following literal
+    fn parse_literal(&mut self, end: usize) -> Result<String> {
+        while self.i < end && matches!(self.get_current_token(), Tokens::Space) {
+            self.inc();
+        }
+        if self.i >= end { bail!("Unexpected end of input") }
+        match self.get_current_token() {
+            Tokens::Literal(str, _) => Ok(str.clone()),
+            token => bail!("Expected literal, got {}", token.to_str())
+        }
     }
 
     fn parse_string_or_array_func_call(&mut self, end: usize) -> Result<DefinedFunctionCall> {
@@ -283,15 +686,20 @@ impl Tree {
         };
         let mut args = Vec::new();
         self.inc();
-        loop {
-            args.push(self.get_value(end, false)?);
-            self.inc();
-            if self.i >= end { break }
+        while self.i < end {
+            match self.get_current_token() {
+                Tokens::Space => { self.inc(); },
+                _ => {
+                    args.push(self.get_value(end, true)?);
+                    self.inc();
+                }
+            }
         }
 
         Ok(DefinedFunctionCall { name, args })
     }
 
+    #[allow(clippy::redundant_pattern_matching)]
     fn parse_for(&mut self, end: usize) -> Result<ForExpression> {
         self.inc();
         let arg_value = self.get_value(end, true)?;
@@ -306,7 +714,10 @@ impl Tree {
             }
             self.inc();
         }
-        let list = self.get_value(end, false)?;
+        let list = match self.parse_range(end)? {
+            Some(range) => range,
+            None => ForValue::Value(self.get_value(end, false)?)
+        };
 
         let mut contents = Vec::new();
 
@@ -326,6 +737,49 @@ impl Tree {
         Ok(ForExpression { arg_key, arg_value, contents, else_contents, list })
     }
 
+    /// Parses a numeric range used as for list. Returns None without moving if the list isn't a range
+    fn parse_range(&mut self, end: usize) -> Result<Option<ForValue>> {
+        let mut i = self.i;
+        while i < end && matches!(self.tokens[i].token, Tokens::Space) {
+            i += 1;
+        }
+        let mut parts: Vec<Vec<Value>> = vec![Vec::new()];
+        let mut inclusive = false;
+        while i < end {
+            match &self.tokens[i].token {
+                Tokens::CommandEnd(_) | Tokens::Space => break,
+                Tokens::Literal(str, _) => {
+                    for (x, segment) in str.split("..").enumerate() {
+                        if x > 0 { parts.push(Vec::new()); }
+                        if !segment.is_empty() {
+                            if segment.parse::<i64>().is_err() { return Ok(None) }
+                            parts.last_mut().unwrap().push(Value::Literal(segment.to_string()));
+                        }
+                    }
+                },
+                // ..= is tokenized as a literal ending with .. followed by =
+                Tokens::ExportSet => {
+                    if parts.len() != 2 || !parts[1].is_empty() || inclusive { return Ok(None) }
+                    inclusive = true;
+                },
+                Tokens::StringVariable(name, _) => parts.last_mut().unwrap().push(Value::Variable(name.clone())),
+                _ => return Ok(None)
+            }
+            i += 1;
+        }
+        if parts.len() < 2 || parts.len() > 3 || parts.iter().any(|part| part.len() != 1) {
+            return Ok(None);
+        }
+        self.i = i;
+        let mut parts = parts.into_iter().map(|mut part| part.pop().unwrap());
+        Ok(Some(ForValue::Range {
+            start: parts.next().unwrap(),
+            end: parts.next().unwrap(),
+            step: parts.next(),
+            inclusive
+        }))
+    }
+
     fn parse_else(&mut self, end: usize) -> Result<Vec<Expression>> {
         loop {
             match self.get_current_token() {
@@ -406,6 +860,20 @@ impl Tree {
         Ok(WhileExpression { condition: Box::new(condition), contents })
     }
 
+    /// Parses the commands of $(...), <(...) or >(...), starting at the opening token. Leaves the cursor on the closing
+    /// parenthesis
+    fn parse_sub_expressions(&mut self, end: usize) -> Result<Vec<Expression>> {
+        self.inc();
+        let (len, lvl) = self.get_parens_vals(end);
+        if lvl != 0 {
+            bail!("Parenthesis do not match");
+        }
+        let sub_end = self.i + len;
+        let expressions = self.parse_sub(sub_end + 1)?;
+        self.i = sub_end;
+        Ok(expressions)
+    }
+
     fn parse_sub(&mut self, end: usize) -> Result<Vec<Expression>> {
         let mut expressions: Vec<Expression> = Vec::new();
         loop {
@@ -415,16 +883,36 @@ impl Tree {
         Ok(expressions)
     }
 
-    fn parse_array_definition(&mut self, end: usize) -> Result<Vec<Value>> {
+    fn parse_array_definition(&mut self, end: usize) -> Result<Value> {
         let mut values: Vec<Value> = Vec::new();
+        let mut entries: Vec<(Value, Value)> = Vec::new();
+        if self.i + 1 == end && matches!(self.get_current_token(), Tokens::ExportSet) {
+            self.inc();
+            return Ok(Value::MapDefinition(entries));
+        }
         loop {
             if self.i >= end { break; }
-            let val = self.get_value(end, true)?;
+            let is_entry = self.i + 1 < end && matches!(self.tokens[self.i + 1].token, Tokens::ExportSet)
+                && matches!(self.get_current_token(), Tokens::Literal(_, _) | Tokens::StringVariable(_, _));
+            if is_entry {
+                let key = match self.get_current_token() {
+                    Tokens::StringVariable(name, _) => Value::Variable(name.clone()),
+                    token => Value::Literal(token.to_str())
+                };
+                self.inc();
+                self.inc();
+                entries.push((key, self.get_value(end, true)?));
+            } else {
+                values.push(self.get_value(end, true)?);
+            }
+            if !entries.is_empty() && !values.is_empty() { bail!("Cannot mix map entries (key=value) and array values") }
             self.inc();
-            values.push(val);
-            if matches!(self.get_current_token(), Tokens::Space) { self.inc(); }
+            if self.i < end && matches!(self.get_current_token(), Tokens::Space) { self.inc(); }
+        }
+        if !entries.is_empty() {
+            return Ok(Value::MapDefinition(entries));
         }
-        Ok(values)
+        Ok(Value::ArrayDefinition(values))
     }
 
     fn get_parens_vals(&self, end: usize) -> (usize, usize) {
@@ -432,7 +920,7 @@ impl Tree {
         let mut lvl = 1;
         for token in &self.tokens[self.i..end] {
             match token.token {
-                Tokens::SubStart => lvl += 1,
+                Tokens::SubStart | Tokens::ArraySubStart | Tokens::ProcessSubStart { .. } => lvl += 1,
                 Tokens::StringFunction(_) => lvl += 1,
                 Tokens::ArrayFunction(_) => lvl += 1,
                 Tokens::ParenthesisStart => lvl += 1,
@@ -455,18 +943,19 @@ impl Tree {
         loop {
             match token {
                 Tokens::Space => {
+                    if stop_on_space && buf.is_empty() && !values.is_empty() { break; }
                     if buf.is_empty() { token = self.inc().get_current_token(); continue; }
                     if stop_on_space { break; }
-                    values.push(Value::Values(buf));
+                    values.push(Tree::get_word(buf));
                     buf = Vec::new();
                     if self.i >= end - 1 { break }
                 },
                 Tokens::CommandEnd(_) => break,
-                Tokens::Literal(str) => buf.push(Value::Literal(str.clone())),
+                Tokens::Literal(str, _) => buf.push(Value::Literal(str.clone())),
                 Tokens::ExportSet => bail!("Unexpected token EXPORT_SET (=)"),
-                Tokens::FileRead => buf.push(Value::Literal(token.to_str())),
+                Tokens::FileRead | Tokens::HereDoc { .. } | Tokens::HereString => buf.push(Value::Literal(token.to_str())),
                 Tokens::Function => buf.push(Value::Literal(token.to_str())),
-                Tokens::FileWrite => buf.push(Value::Literal(token.to_str())),
+                Tokens::FileWrite { .. } | Tokens::FileWriteAll { .. } | Tokens::FileDuplicate { .. } => buf.push(Value::Literal(token.to_str())),
                 Tokens::RedirectInto => bail!("Unexpected token REDIRECT (|)"),
                 Tokens::ParenthesisEnd => bail!("Unexpected token FUNCTION CALL END ())"),
                 Tokens::StringFunction(_) | Tokens::ArrayFunction(_) => {
@@ -476,7 +965,7 @@ impl Tree {
                     if lvl != 0 {
                         bail!("Parenthesis do not match");
                     }
-                    let val = self.parse_string_or_array_func_call(self.i + len)?;
+                    let val = self.parse_string_or_array_func_call(self.i + len + 1)?;
                     return Ok(Value::ValueFunction(val));
                 },
                 Tokens::ParenthesisStart => bail!("Parenthesis not yet implemented"),
@@ -500,19 +989,25 @@ impl Tree {
                     if lvl != 0 {
                         bail!("Parenthesis do not match");
                     }
-                    let val = Value::ArrayDefinition(self.parse_array_definition(self.i + len)?);
+                    let val = self.parse_array_definition(self.i + len)?;
+                    if !buf.is_empty() {
+                        values.push(Tree::get_word(std::mem::take(&mut buf)));
+                    }
                     values.push(val);
                 },
                 Tokens::ArrayEnd => bail!("Unexpected token ARRAY END (])"),
                 Tokens::SubStart => {
-                    let (len, lvl) = self.get_parens_vals(end);
-                    self.inc();
-                    if lvl != 0 {
-                        bail!("Parenthesis do not match");
-                    }
-                    let val = Value::Expressions(self.parse_sub(self.i + len)?);
-                    self.inc();
-                    return Ok(val);
+                    let val = Value::Expressions(self.parse_sub_expressions(end)?);
+                    buf.push(val);
+                },
+                Tokens::ArraySubStart => {
+                    let val = Value::ArrayExpressions(self.parse_sub_expressions(end)?);
+                    buf.push(val);
+                },
+                Tokens::ProcessSubStart { write } => {
+                    let write = *write;
+                    let val = Value::ProcessSubstitution(self.parse_sub_expressions(end)?, write);
+                    buf.push(val);
                 },
                 Tokens::Else => buf.push(Value::Literal(token.to_str())),
                 Tokens::End => buf.push(Value::Literal(token.to_str())),
@@ -521,33 +1016,32 @@ impl Tree {
                 Tokens::Let => buf.push(Value::Literal(token.to_str())),
                 Tokens::While => buf.push(Value::Literal(token.to_str())),
                 Tokens::StringVariable(str, _) => {
-                    if !buf.is_empty() {
-                        values.push(Value::Values(buf));
-                        buf = Vec::new();
-                    }
-                    values.push(Value::Variable(str.clone()));
+                    let name = str.clone();
+                    let val = self.parse_indexes(Value::Variable(name), end)?;
+                    buf.push(val);
                 },
                 Tokens::ArrayVariable(str, _) => {
-                    if !buf.is_empty() {
-                        values.push(Value::Values(buf));
-                        buf = Vec::new();
-                    }
-                    values.push(Value::ArrayVariable(str.clone()));
+                    let name = str.clone();
+                    let val = self.parse_indexes(Value::ArrayVariable(name), end)?;
+                    buf.push(val);
                 },
+                Tokens::Math(str) => {
+                    buf.push(Value::Math(str.clone()));
+                },
+                Tokens::ParameterExpansion(str) => buf.push(parse_parameter(str)?),
+                Tokens::Tilde(user) => buf.push(Value::Tilde(user.clone())),
                 Tokens::And => bail!("Unexpected AND (&&)"),
                 Tokens::Or => bail!("Unexpected OR (||)"),
                 Tokens::Break => buf.push(Value::Literal(token.to_str())),
+                Tokens::Continue => buf.push(Value::Literal(token.to_str())),
+                Tokens::Return => buf.push(Value::Literal(token.to_str())),
                 Tokens::JobCommandEnd => bail!("Unexpected job command end (&)"),
             }
             if self.i >= end - 1 { break }
             token = self.inc().get_current_token();
         }
         if !buf.is_empty() {
-            if buf.len() == 1 {
-                values.push(buf.into_iter().next().unwrap());
-            } else {
-                values.push(Value::Values(buf));
-            }
+            values.push(Tree::get_word(buf));
         }
         if values.len() == 1 {
             return Ok(values.into_iter().next().unwrap());
@@ -555,27 +1049,55 @@ impl Tree {
         Ok(Value::Values(values))
     }
 
+    /// Joins the parts of a word, like "a$b"
+    fn get_word(mut parts: Vec<Value>) -> Value {
+        if parts.len() == 1 {
+            return parts.pop().unwrap();
+        }
+        Value::Concat(parts)
+    }
+
+    fn negated(expr: Expression, negate: bool) -> Expression {
+        if !negate {
+            return expr;
+        }
+        Expression::NotExpression(NotExpression { expr: Box::new(expr) })
+    }
+
+    #[allow(clippy::redundant_pattern_matching)]
     fn get_expression(&mut self, end: usize) -> Result<Expression> {
         let mut expr: Option<Expression> = None;
+        let mut negate = false;
         let mut token = self.get_current_token();
         loop {
             match token {
                 Tokens::Space => {self.inc();},
                 Tokens::CommandEnd(_) => { if matches!(expr, Some(_)) { break }; self.inc();},
-                Tokens::Literal(_) => if matches!(expr, Some(_)) {
+                // ! followed by a space negates the pipeline
+                Tokens::Literal(str, false) if str == "!" && expr.is_none() && !negate
+                    && matches!(self.tokens.get(self.i + 1).map(|t| &t.token), Some(Tokens::Space)) => {
+                    negate = true;
+                    self.inc();
+                },
+                Tokens::Literal(_, _) => if matches!(expr, Some(_)) {
                     bail!("Unexpected literal. After file redirect, you need to use a semicolon or newline.");
                 } else {
                     expr = Some(self.parse_call(end)?);
                 },
                 Tokens::ExportSet => bail!("Unexpected token EXPORT SET (=)"),
                 Tokens::Function => return Ok(Expression::Function(self.parse_function(end)?)),
-                Tokens::FileRead => expr = Some(self.parse_read(expr, end)?),
-                Tokens::FileWrite => expr = Some(self.parse_write(expr, end)?),
+                Tokens::FileRead | Tokens::HereDoc { .. } | Tokens::HereString => expr = Some(self.parse_read(expr, end)?),
+                Tokens::FileWrite { .. } | Tokens::FileWriteAll { .. } | Tokens::FileDuplicate { .. } => expr = Some(self.parse_write(expr, end)?),
                 Tokens::RedirectInto => match expr {
                     None => bail!("Unexpected token REDIRECT (|)"),
                     Some(_) => {
                         self.i += 1;
-                        expr = Some(Expression::RedirectTargetExpression(RedirectTargetExpression { source: Box::new(expr.unwrap()), target: Box::new(self.get_expression(end)?) }));
+                        let mut commands = vec![expr.unwrap()];
+                        match self.get_expression(end)? {
+                            Expression::PipelineExpression(rest) => commands.extend(rest.commands),
+                            target => commands.push(target)
+                        }
+                        expr = Some(Expression::PipelineExpression(PipelineExpression { commands }));
                     }
                 },
                 Tokens::ParenthesisStart => if matches!(expr, Some(_)) {
@@ -590,11 +1112,15 @@ impl Tree {
                     self.inc();
                 },
                 Tokens::ParenthesisEnd => bail!("Unexpected token PARENTHESIS END ())"),
+                // [ followed by a space is the test builtin
+                Tokens::ArrayStart if expr.is_none() && matches!(self.tokens.get(self.i + 1).map(|t| &t.token), Some(Tokens::Space)) => {
+                    expr = Some(self.parse_call(end)?);
+                },
                 Tokens::ArrayStart => bail!("Arrays not yet implemented"),
                 Tokens::ArrayEnd => bail!("Unexpected token ARRAY END (])"),
                 Tokens::ArrayFunction(_) => bail!("Unexpected array function"),
                 Tokens::StringFunction(_) => bail!("Unexpected string function"),
-                Tokens::SubStart => match expr {
+                Tokens::SubStart | Tokens::ArraySubStart | Tokens::ProcessSubStart { .. } => match expr {
                     Some(_) => bail!("Unexpected literal. After file redirect, you need to use a semicolon or newline."),
                     _ => expr = Some(self.parse_call(end)?)
                 },
@@ -610,7 +1136,7 @@ impl Tree {
                 }
                 Tokens::Let => return self.parse_let(end),
                 Tokens::While => return Ok(Expression::WhileExpression(self.parse_while(end)?)),
-                Tokens::StringVariable(_, _) => if matches!(expr, Some(_)) {
+                Tokens::StringVariable(_, _) | Tokens::Math(_) | Tokens::ParameterExpansion(_) | Tokens::Tilde(_) => if matches!(expr, Some(_)) {
                     bail!("Unexpected variable. After file redirect, you need to use a semicolon or newline.");
                 } else {
                     expr = Some(self.parse_call(end)?);
@@ -620,14 +1146,14 @@ impl Tree {
                     None => bail!("Unexpected AND (&&)"),
                     Some(_) => {
                         self.inc();
-                        expr = Some(Expression::AndExpression(AndExpression { first: Box::new(expr.unwrap()), second: Box::new(self.get_expression(end)?) }));
+                        expr = Some(Expression::AndExpression(AndExpression { first: Box::new(Tree::negated(expr.unwrap(), std::mem::take(&mut negate))), second: Box::new(self.get_expression(end)?) }));
                     }
                 },
                 Tokens::Or => match expr {
                     None => bail!("Unexpected OR (||)"),
                     Some(_) => {
                         self.inc();
-                        expr = Some(Expression::OrExpression(OrExpression { first: Box::new(expr.unwrap()), second: Box::new(self.get_expression(end)?) }));
+                        expr = Some(Expression::OrExpression(OrExpression { first: Box::new(Tree::negated(expr.unwrap(), std::mem::take(&mut negate))), second: Box::new(self.get_expression(end)?) }));
                     }
                 },
                 Tokens::Break => match expr {
@@ -637,13 +1163,41 @@ impl Tree {
                     },
                     Some(_) => bail!("Unexpected break")
                 }
-                Tokens::JobCommandEnd => bail!("Jobs not yet implemented")
+                Tokens::Continue => match expr {
+                    None => {
+                        self.inc();
+                        expr = Some(Expression::ContinueExpression(ContinueExpression { num: Box::new(self.get_value(end, false)?)}));
+                    },
+                    Some(_) => bail!("Unexpected continue")
+                }
+                Tokens::Return => match expr {
+                    None => {
+                        self.inc();
+                        let value = Box::new(self.get_value(end, false)?);
+                        // value functions leave the cursor on their closing parenthesis
+                        if self.i < end && !matches!(self.get_current_token(), Tokens::CommandEnd(_)) { self.inc(); }
+                        expr = Some(Expression::ReturnExpression(ReturnExpression { value }));
+                    },
+                    Some(_) => bail!("Unexpected return")
+                }
+                Tokens::JobCommandEnd => match expr {
+                    None => bail!("Unexpected job command end (&)"),
+                    Some(_) => {
+                        self.inc();
+                        return Ok(Expression::JobCommand(Box::new(Tree::negated(expr.unwrap(), negate))));
+                    }
+                }
             };
             if self.i >= end - 1 { break }
             token = self.get_current_token();
         }
         match expr {
-            Some(expr) => Ok(expr),
+            // a trailing & at the end of input isn't reached by the loop above
+            Some(expr) if self.i < end && matches!(self.get_current_token(), Tokens::JobCommandEnd) => {
+                self.inc();
+                Ok(Expression::JobCommand(Box::new(Tree::negated(expr, negate))))
+            },
+            Some(expr) => Ok(Tree::negated(expr, negate)),
             None => bail!("No expression found")
         }
     }
@@ -655,6 +1209,21 @@ impl Tree {
     fn get_current_token(&self) -> &Tokens { &self.tokens.get(self.i).unwrap().token }
 }
 
+/// Only stdout and stderr can be redirected
+fn check_fd(fd: i32) -> Result<i32> {
+    match fd {
+        1 | 2 => Ok(fd),
+        fd => bail!("Unsupported file descriptor {}, only 1 (stdout) and 2 (stderr) can be redirected", fd)
+    }
+}
+
+/// Splits name:type at its colon. The :: of env::NAME isn't a type
+fn split_type(str: &str) -> Option<(&str, &str)> {
+    let bytes = str.as_bytes();
+    let i = (0..bytes.len()).find(|i| bytes[*i] == b':' && (*i == 0 || bytes[i - 1] != b':') && bytes.get(i + 1) != Some(&b':'))?;
+    Some((&str[..i], &str[i + 1..]))
+}
+
 pub fn build_tree(tokens: Vec<Token>) -> Result<Vec<Expression>> {
     // dbg!(&tokens);
     let mut expressions: Vec<Expression> = Vec::new();
@@ -1,31 +1,297 @@
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::Read;
-use std::process::Command;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, IntoRawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::ffi::{CStr, CString};
 use std::thread;
-use crate::parser::ast::{AndExpression, BreakExpression, CommandValue, Expression, FileSourceExpression, FileTargetExpression, ForExpression, IfExpression, LetExpression, OrExpression, RedirectTargetExpression, Value, WhileExpression};
-use crate::parser::vars::{AnyFunction, Context, ReaderOverride, Variable, WriterOverride};
-use anyhow::{Result, bail, Context as AnyhowContext};
+use crate::parser::ast::{AndExpression, BreakExpression, CommandValue, ContinueExpression, Expression, FileSourceExpression, FileTargetExpression, ForExpression, ForValue, FunctionDefinitionExpression, IfExpression, IndexValue, LetExpression, NotExpression, OrExpression, ParameterOperator, parse_quoted, PipelineExpression, RedirectMode, ReturnExpression, SourceMode, Value, WhileExpression};
+use crate::parser::glob;
+use crate::parser::math;
+use crate::parser::vars::{AnyFunction, Context, Overrides, ReaderOverride, Variable, WriterOverride};
+use os_pipe::PipeReader;
+use anyhow::{Result, anyhow, bail, Context as AnyhowContext};
+
+/// Function run in-process as a stage of a pipeline
+#[derive(Debug)]
+enum FunctionCallTarget {
+    Native(fn(&mut Context, Vec<Variable>) -> Result<Variable>),
+    UserDefined(FunctionDefinitionExpression)
+}
+
+#[derive(Debug)]
+struct FunctionCall {
+    name: String,
+    target: FunctionCallTarget,
+    args: Vec<Variable>,
+    /// overrides captured when the call was built, so that it reads from and writes to its place in the pipeline
+    overrides: Overrides
+}
+
+impl FunctionCall {
+    fn run(self, ctx: &mut Context) -> Result<i32> {
+        let depth = ctx.scopes.len();
+        ctx.add_scope();
+        let scope = ctx.scopes.last_mut().unwrap();
+        scope.stdin_override = self.overrides.stdin;
+        scope.stdout_override = self.overrides.stdout;
+        scope.stderr_override = self.overrides.stderr;
+        let res = match self.target {
+            // like in other shells, a failing builtin prints its error and sets $? instead of stopping the script
+            FunctionCallTarget::Native(func) => func(ctx, self.args).or_else(|err| {
+                let _ = writeln!(ctx.get_stderr()?, "rush: {}", err);
+                Ok(Variable::I32(1))
+            }),
+            FunctionCallTarget::UserDefined(mut func) => call_function(ctx, &mut func, self.args)
+        };
+        ctx.restore_scopes(depth);
+        Ok(get_exit_code(&res?))
+    }
+
+    /// Runs the call in a forked copy of the shell, so that it runs at the same time as the other stages of its
+    /// pipeline. Returns the process id
+    fn fork(mut self, ctx: &mut Context, fds: &[usize]) -> Result<i32> {
+        // output buffered by the shell would be written by both processes
+        let _ = std::io::stdout().flush();
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(std::io::Error::last_os_error()).with_context(|| format!("Failed to start {}", self.name));
+        }
+        if pid > 0 {
+            return Ok(pid);
+        }
+        self.take_streams(ctx, fds);
+        inherit(&fds.iter().map(|fd| *fd as i32).collect::<Vec<_>>());
+        let code = self.run(ctx).unwrap_or_else(|err| {
+            eprintln!("rush: {}", err);
+            1
+        });
+        let _ = std::io::stdout().flush();
+        unsafe { libc::_exit(code) }
+    }
+
+    /// Makes the overrides of the call the standard streams of the forked process. The descriptors it inherited from
+    /// the rest of the shell are closed, as they would keep the pipes of other stages open
+    fn take_streams(&mut self, ctx: &mut Context, fds: &[usize]) {
+        if let Some(stdin) = self.overrides.stdin.take() {
+            let fd = match &stdin {
+                ReaderOverride::Pipe(pipe) => pipe.as_raw_fd(),
+                ReaderOverride::File(file) => file.as_raw_fd()
+            };
+            unsafe { libc::dup2(fd, 0); }
+        }
+        for (target, writer) in [(1, &mut self.overrides.stdout), (2, &mut self.overrides.stderr)] {
+            let fd = match writer {
+                Some(WriterOverride::Pipe(pipe)) => pipe.as_raw_fd(),
+                Some(WriterOverride::File(file)) => file.as_raw_fd(),
+                Some(WriterOverride::Closed) => {
+                    unsafe { libc::close(target); }
+                    continue;
+                },
+                None => continue
+            };
+            unsafe { libc::dup2(fd, target); }
+            *writer = None;
+        }
+        for scope in &mut ctx.scopes {
+            scope.stdin_override = None;
+            scope.stdout_override = None;
+            scope.stderr_override = None;
+            scope.fd.retain(|fd| fds.contains(fd));
+        }
+        let open: Vec<i32> = match std::fs::read_dir("/proc/self/fd") {
+            Ok(dir) => dir.filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok()).collect(),
+            Err(_) => return
+        };
+        for fd in open {
+            if fd > 2 && !fds.contains(&(fd as usize)) {
+                unsafe { libc::close(fd); }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Stage {
+    Command(Command),
+    FunctionCall(FunctionCall)
+}
+
+/// Stage of a pipeline after it was started
+enum SpawnedStage {
+    Child(Child),
+    /// function call running in a forked copy of the shell
+    Forked(i32),
+    /// function call already run in-process, with its exit code
+    Done(i32)
+}
+
+impl SpawnedStage {
+    /// Waits for the stage to exit, returning its exit code (-1 when it was killed by a signal)
+    fn wait(self) -> Result<i32> {
+        Ok(match self {
+            SpawnedStage::Child(mut child) => child.wait().with_context(|| "Command failed")?.code().unwrap_or(-1),
+            SpawnedStage::Forked(pid) => {
+                let mut status = 0;
+                if unsafe { libc::waitpid(pid, &mut status, 0) } < 0 {
+                    return Err(std::io::Error::last_os_error()).with_context(|| "Command failed");
+                }
+                if libc::WIFEXITED(status) { libc::WEXITSTATUS(status) } else { -1 }
+            },
+            SpawnedStage::Done(code) => code
+        })
+    }
+
+    /// Stops a stage started before a later one failed, so it isn't left running or as a zombie
+    fn abort(self) {
+        match self {
+            SpawnedStage::Child(mut child) => {
+                let _ = child.kill();
+                let _ = child.wait();
+            },
+            SpawnedStage::Forked(pid) => unsafe {
+                libc::kill(pid, libc::SIGKILL);
+                libc::waitpid(pid, std::ptr::null_mut(), 0);
+            },
+            SpawnedStage::Done(_) => {}
+        }
+    }
+}
+
+/// Commands connected by pipes, each stage reading the output of the one before it
+#[derive(Debug, Default)]
+struct Pipeline {
+    stages: Vec<Stage>,
+    /// process substitution file descriptors used by the stages, closed once they are started
+    fds: Vec<usize>
+}
+
+impl Drop for Pipeline {
+    /// Closes the process substitution descriptors of a pipeline that wasn't started
+    fn drop(&mut self) {
+        for fd in self.fds.drain(..) {
+            unsafe { libc::close(fd as i32); }
+        }
+    }
+}
+
+/// Lets the process substitution descriptors, which are closed on exec like all the shell's own descriptors, be
+/// inherited by the commands run next in this process
+fn inherit(fds: &[i32]) {
+    for fd in fds {
+        unsafe { libc::fcntl(*fd, libc::F_SETFD, 0); }
+    }
+}
+
+impl Pipeline {
+    /// Starts every stage before any of them is waited on. Builtins and functions run in a forked copy of the shell,
+    /// except as the last stage, which runs in the shell itself so that `cd` or `let` still apply to it. With
+    /// background, they are all forked
+    fn start(mut self, ctx: &mut Context, background: bool) -> Result<Vec<SpawnedStage>> {
+        let fds = std::mem::take(&mut self.fds);
+        let stages = std::mem::take(&mut self.stages);
+        let count = stages.len();
+        let mut started = Vec::new();
+        let mut res = Ok(());
+        for (i, stage) in stages.into_iter().enumerate() {
+            let stage = match stage {
+                Stage::Command(mut command) => {
+                    let name = command.get_program().to_str().unwrap_or("unknown").to_string();
+                    command.spawn().map(SpawnedStage::Child).with_context(|| "Failed to spawn process ".to_string() + &name)
+                },
+                Stage::FunctionCall(call) if background || i + 1 < count => call.fork(ctx, &fds).map(SpawnedStage::Forked),
+                Stage::FunctionCall(call) => {
+                    // the other stages are already started, only the commands of the call inherit the descriptors
+                    inherit(&fds.iter().map(|fd| *fd as i32).collect::<Vec<_>>());
+                    call.run(ctx).map(SpawnedStage::Done)
+                }
+            };
+            match stage {
+                Ok(stage) => started.push(stage),
+                Err(err) => {
+                    res = Err(err);
+                    break;
+                }
+            }
+        }
+        for fd in fds {
+            unsafe { libc::close(fd as i32); }
+        }
+        match res {
+            Ok(()) => Ok(started),
+            Err(err) => {
+                for stage in started {
+                    stage.abort();
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Runs the stages and waits for them. The exit status of every stage is stored in PIPESTATUS, the status of the
+    /// pipeline is the one of the last stage, or of the last failed one with pipefail
+    fn exec(self, ctx: &mut Context) -> Result<Option<i32>> {
+        if self.stages.is_empty() {
+            return Ok(None);
+        }
+        let mut statuses = Vec::new();
+        let mut stages = self.start(ctx, false)?.into_iter();
+        while let Some(stage) = stages.next() {
+            match stage.wait() {
+                Ok(status) => statuses.push(status),
+                Err(err) => {
+                    stages.for_each(SpawnedStage::abort);
+                    return Err(err);
+                }
+            }
+        }
+        let last = *statuses.last().unwrap();
+        let code = match statuses.iter().rev().find(|status| **status != 0) {
+            Some(failed) if ctx.pipefail => *failed,
+            _ => last
+        };
+        ctx.set_var(String::from("PIPESTATUS"), Variable::Array(statuses.into_iter().map(Variable::I32).collect()));
+        Ok(Some(code))
+    }
+
+    /// Command line of all stages, joined with |
+    fn describe(&self) -> String {
+        let mut commands = Vec::new();
+        for stage in &self.stages {
+            let (name, args): (String, Vec<String>) = match stage {
+                Stage::Command(command) => (
+                    command.get_program().to_string_lossy().to_string(),
+                    command.get_args().map(|arg| arg.to_string_lossy().to_string()).collect()
+                ),
+                Stage::FunctionCall(call) => (call.name.clone(), call.args.iter().map(|arg| arg.to_string()).collect())
+            };
+            let mut str = name;
+            for arg in args {
+                str += " ";
+                str += &arg;
+            }
+            commands.push(str);
+        }
+        commands.join(" | ")
+    }
+}
 
 #[derive(Debug, Default)]
 struct ExecResult {
-    commands: Vec<Command>
+    pipeline: Pipeline,
+    /// exit code of expressions without stages (like starting a job)
+    code: Option<i32>,
+    /// ! pipeline, inverts the exit code
+    negate: bool
 }
 
 impl ExecResult {
+    /// Runs the pipeline and sets $? to its exit code, or the one of the expression if it has no stages
     fn exec(self, ctx: &mut Context) -> Result<Option<i32>> {
-        let mut children = Vec::new();
-        for mut command in self.commands {
-            let name = command.get_program().to_str().unwrap_or("unknown").to_string();
-            let out = command.spawn()
-                .with_context(|| "Failed to spawn process ".to_string() + &name)?;
-            drop(command);
-            children.push(out);
-        }
-        let mut code = None;
-        for mut child in children {
-            let out = child.wait()
-                .with_context(|| "Command failed")?;
-            code = Some(out.code().unwrap_or(-1));
+        let mut code = self.pipeline.exec(ctx)?.or(self.code);
+        if self.negate {
+            code = Some(if code.unwrap_or(0) == 0 { 1 } else { 0 });
         }
         if let Some(code) = code {
             ctx.set_var(String::from("?"), Variable::I32(code));
@@ -34,7 +300,11 @@ impl ExecResult {
     }
 
     fn merge(&mut self, mut other: ExecResult) {
-        self.commands.append(&mut other.commands);
+        self.pipeline.stages.append(&mut other.pipeline.stages);
+        self.pipeline.fds.append(&mut other.pipeline.fds);
+        if other.code.is_some() {
+            self.code = other.code;
+        }
     }
 }
 
@@ -58,29 +328,47 @@ impl GetValue for CommandValue {
 impl GetValue for Value {
     fn get(self: &mut Value, ctx: &mut Context) -> Result<Variable> {
         match self {
-            Value::Literal(str) => {
+            Value::Literal(str) | Value::Glob(str) => {
                 Ok(Variable::String(str.clone()))
             },
             Value::Variable(str) => Ok(ctx.get_var(str).unwrap_or(&mut Variable::String(String::from(""))).clone()),
+            Value::Parameter(name, operator) => expand_parameter(name, operator, ctx),
+            Value::Tilde(user) => Ok(Variable::String(expand_tilde(user, ctx)?)),
+            Value::Math(expr) => math::eval(expr, ctx),
             Value::ArrayVariable(str) => Ok(ctx.get_var(str).unwrap_or(&mut Variable::Array(Vec::new())).clone()),
-            Value::Expressions(expressions) => {
+            Value::Expressions(expressions) => Ok(Variable::String(capture_output(expressions, ctx)?)),
+            Value::ArrayExpressions(expressions) => {
+                let output = capture_output(expressions, ctx)?;
+                let separators = ctx.get_var("IFS").map(|ifs| ifs.to_string()).unwrap_or_else(|| String::from("\n"));
+                Ok(Variable::Array(split_fields(&output, &separators)))
+            },
+            Value::ProcessSubstitution(expressions, write) => {
+                let (reader, writer) = os_pipe::pipe()?;
                 ctx.add_scope();
-                let (mut reader, writer) = os_pipe::pipe()?;
-                let mut data = String::new();
-                thread::scope(|s| -> Result<()> {
+                let fd = if *write {
+                    ctx.scopes.last_mut().unwrap().stdin_override = Some(ReaderOverride::Pipe(reader));
+                    writer.into_raw_fd()
+                } else {
                     ctx.scopes.last_mut().unwrap().stdout_override = Some(WriterOverride::Pipe(writer));
-                    s.spawn(|| -> Result<()> {
-                        let mut buf = Vec::new();
-                        reader.read_to_end(&mut buf)?;
-                        data = String::from_utf8_lossy(&buf).to_string();
-                        Ok(())
-                    });
-                    expressions.exec(ctx)?.exec(ctx)?;
-                    Ok(())
-                })?;
+                    reader.into_raw_fd()
+                };
+                // the commands run while the main command uses the pipe, so builtins and functions are forked too
+                let res = expressions.exec(ctx).and_then(|res| res.pipeline.start(ctx, true));
                 ctx.pop_scope();
-                Ok(Variable::String(data))
-            },
+                let stages = match res {
+                    Ok(stages) => stages,
+                    Err(err) => {
+                        unsafe { libc::close(fd); }
+                        return Err(err);
+                    }
+                };
+                // the commands finish once the main command is done with the pipe, so they are reaped in the background
+                for stage in stages {
+                    thread::spawn(move || stage.wait());
+                }
+                ctx.scopes.last_mut().unwrap().fd.push(fd as usize);
+                Ok(Variable::String(format!("/dev/fd/{}", fd)))
+            }
             Value::Values(vec) | Value::ArrayDefinition(vec) => {
                 let mut out = Vec::new();
                 for val in vec {
@@ -88,20 +376,172 @@ impl GetValue for Value {
                 }
                 Ok(Variable::Array(out))
             }
+            Value::Concat(parts) => {
+                let mut out = String::new();
+                for part in parts {
+                    out += &part.get(ctx)?.to_string();
+                }
+                Ok(Variable::String(out))
+            }
+            Value::MapDefinition(entries) => {
+                let mut map = HashMap::new();
+                for (key, val) in entries {
+                    map.insert(key.get(ctx)?.to_string(), val.get(ctx)?);
+                }
+                Ok(Variable::HMap(map))
+            }
+            Value::Index(value, index) => {
+                let value = value.get(ctx)?;
+                match index.as_mut() {
+                    IndexValue::Key(key) => Ok(value.index(&key.get(ctx)?)?.clone()),
+                    IndexValue::Range { start, end, inclusive } => {
+                        let start = start.as_mut().map(|start| start.get(ctx)).transpose()?;
+                        let end = end.as_mut().map(|end| end.get(ctx)).transpose()?;
+                        value.slice(start.as_ref(), end.as_ref(), *inclusive)
+                    },
+                    IndexValue::Append => bail!("[+] can only be used to append in let")
+                }
+            }
             Value::ValueFunction(call) => {
                 let args = get_variables(ctx, &mut call.args)?;
-                let func = ctx.get_func(call.name.as_str()).with_context(|| format!("Function {} not found", call.name))?;
+                let is_array = call.name.starts_with('@');
+                // user defined functions are declared without the $ or @ prefix
+                let func = match ctx.get_func(call.name.as_str()) {
+                    Some(func) => func,
+                    None => ctx.get_func(&call.name[1..]).with_context(|| format!("Function {} not found", call.name))?
+                };
                 match func {
                     AnyFunction::Native(func) => {
                         (func.func)(ctx, args)
                     }
-                    AnyFunction::UserDefined(_) => todo!("User defined functions are not yet supported")
+                    AnyFunction::UserDefined(func) => {
+                        let mut func = func.clone();
+                        let res = call_function(ctx, &mut func, args)?;
+                        Ok(match res {
+                            Variable::Array(_) => res,
+                            res if is_array => Variable::Array(vec![res]),
+                            res => res
+                        })
+                    }
                 }
             }
         }
     }
 }
 
+/// Runs the commands of $(...) or @(...) and returns their output without trailing newlines. $? is set to their exit code
+fn capture_output(expressions: &mut Vec<Expression>, ctx: &mut Context) -> Result<String> {
+    let depth = ctx.scopes.len();
+    ctx.add_scope();
+    let (mut reader, writer) = os_pipe::pipe()?;
+    ctx.scopes.last_mut().unwrap().stdout_override = Some(WriterOverride::Pipe(writer));
+    let data = thread::scope(|s| -> Result<(String, Option<i32>)> {
+        let output = s.spawn(move || -> std::io::Result<String> {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf)?;
+            Ok(String::from_utf8_lossy(&buf).to_string())
+        });
+        let res = expressions.exec(ctx).and_then(|res| res.exec(ctx));
+        // the output is only complete once every copy of the pipe is closed
+        ctx.restore_scopes(depth);
+        let code = res?;
+        Ok((output.join().map_err(|_| anyhow!("Failed to read command output"))??, code))
+    });
+    let (data, code) = data?;
+    if let Some(code) = code {
+        ctx.set_var(String::from("?"), Variable::I32(code));
+    }
+    // like other shells, trailing newlines are removed
+    Ok(data.trim_end_matches('\n').to_string())
+}
+
+/// Splits output on the separator characters. Like in other shells, empty fields are only kept between separators
+/// that aren't whitespace, so a run of spaces or newlines is a single separator
+fn split_fields(output: &str, separators: &str) -> Vec<Variable> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    for letter in output.chars() {
+        if !separators.contains(letter) {
+            field.push(letter);
+        } else if !field.is_empty() || !letter.is_whitespace() {
+            fields.push(Variable::String(std::mem::take(&mut field)));
+        }
+    }
+    if !field.is_empty() {
+        fields.push(Variable::String(field));
+    }
+    fields
+}
+
+/// Runs a command and returns its output, like $(name args)
+pub fn capture_command(ctx: &mut Context, name: &str, args: Vec<Variable>) -> Result<String> {
+    let mut command = vec![CommandValue::Value(Value::Literal(name.to_string()))];
+    command.extend(args.into_iter().map(|arg| CommandValue::Value(Value::Literal(arg.to_string()))));
+    capture_output(&mut vec![Expression::Command(command)], ctx)
+}
+
+/// Runs a user defined function in a new scope with its arguments bound as variables, returning the value of its return statement
+/// or the exit code of the last command
+fn call_function(ctx: &mut Context, func: &mut FunctionDefinitionExpression, args: Vec<Variable>) -> Result<Variable> {
+    let depth = ctx.scopes.len();
+    ctx.add_scope();
+    let res = bind_arguments(ctx, func, args).and_then(|_| func.body.exec(ctx)?.exec(ctx));
+    ctx.restore_scopes(depth);
+    // break and continue don't leave the function, and a failed call doesn't return a value
+    let (break_num, continue_num) = (ctx.break_num, ctx.continue_num);
+    ctx.break_num = 0;
+    ctx.continue_num = 0;
+    let value = ctx.return_value.take();
+    let code = res?;
+    if break_num > 0 { bail!("Too many break statements") }
+    if continue_num > 0 { bail!("Too many continue statements") }
+    Ok(value.unwrap_or(Variable::I32(code.unwrap_or(0))))
+}
+
+/// Sets the arguments of a function call as variables of the current scope
+fn bind_arguments(ctx: &mut Context, func: &FunctionDefinitionExpression, args: Vec<Variable>) -> Result<()> {
+    for (i, arg) in func.args.iter().enumerate() {
+        let mut val = args.get(i).cloned().unwrap_or_else(|| Variable::String(String::new()));
+        if let Some(vartype) = &arg.vartype {
+            val = convert_to_type(&arg.name, vartype, val).with_context(|| format!("Invalid argument for function {}", func.name))?;
+        }
+        ctx.set_var(arg.name.clone(), val);
+    }
+    ctx.set_var(String::from("argv"), Variable::Array(args));
+    Ok(())
+}
+
+/// Converts a function return value to an exit code
+fn get_exit_code(val: &Variable) -> i32 {
+    match val {
+        Variable::I32(num) => *num,
+        Variable::I64(num) => *num as i32,
+        Variable::I128(num) => *num as i32,
+        Variable::U32(num) => *num as i32,
+        Variable::U64(num) => *num as i32,
+        Variable::U128(num) => *num as i32,
+        Variable::Bool(val) => if *val { 0 } else { 1 },
+        Variable::String(str) => str.parse().unwrap_or(0),
+        _ => 0
+    }
+}
+
+/// Converts a value assigned to a typed variable (let x: i64 = ...) to the variable's type
+fn convert_to_type(name: &str, vartype: &str, val: Variable) -> Result<Variable> {
+    let mismatch = || format!("Cannot assign {} to {}, expected {}", val, name, vartype);
+    if let Some(num) = math::cast(&val, vartype).with_context(mismatch)? {
+        return Ok(num);
+    }
+    Ok(match (vartype, &val) {
+        ("string", Variable::Array(_) | Variable::HMap(_)) => bail!(mismatch()),
+        ("string", val) => Variable::String(val.to_string()),
+        ("bool", Variable::Bool(_)) | ("array", Variable::Array(_)) | ("map", Variable::HMap(_)) => val,
+        ("bool", Variable::String(str)) if str == "true" || str == "false" => Variable::Bool(str == "true"),
+        ("bool" | "array" | "map", _) => bail!(mismatch()),
+        _ => bail!("Unknown type {} for {}. Supported types are i32, i64, i128, u32, u64, u128, f32, f64, string, bool, array and map", vartype, name)
+    })
+}
+
 fn get_variables(ctx: &mut Context, args: &mut Vec<Value>) -> Result<Vec<Variable>> {
     let mut out = Vec::new();
     for arg in args {
@@ -115,22 +555,69 @@ impl ExecExpression for Expression {
         match self {
             Expression::LetExpression(expr) => expr.exec(ctx),
             Expression::Command(expr) => expr.exec(ctx),
-            Expression::JobCommand(_) => todo!("Jobs"),
-            Expression::Function(_) => todo!("Function definition"),
+            Expression::JobCommand(expr) => exec_job(expr, ctx),
+            Expression::Function(expr) => expr.exec(ctx),
             Expression::IfExpression(expr) => expr.exec(ctx),
             Expression::WhileExpression(expr) => expr.exec(ctx),
             Expression::ForExpression(expr) => expr.exec(ctx),
-            Expression::RedirectTargetExpression(expr) => expr.exec(ctx),
+            Expression::PipelineExpression(expr) => expr.exec(ctx),
             Expression::FileTargetExpression(expr) => expr.exec(ctx),
             Expression::FileSourceExpression(expr) => expr.exec(ctx),
             Expression::Expressions(expr) => expr.exec(ctx),
             Expression::OrExpression(expr) => expr.exec(ctx),
             Expression::AndExpression(expr) => expr.exec(ctx),
-            Expression::BreakExpression(expr) => expr.exec(ctx)
+            Expression::NotExpression(expr) => expr.exec(ctx),
+            Expression::BreakExpression(expr) => expr.exec(ctx),
+            Expression::ContinueExpression(expr) => expr.exec(ctx),
+            Expression::ReturnExpression(expr) => expr.exec(ctx)
         }
     }
 }
 
+/// Spawns the expression as a background job without waiting for it
+fn exec_job(expr: &mut Expression, ctx: &mut Context) -> Result<ExecResult> {
+    if ctx.is_unwinding() { return Ok(ExecResult::default()) }
+    let res = expr.exec(ctx)?;
+    // builtins and functions run in the shell itself, they can't be left running while it reads the next command
+    if let Some(Stage::FunctionCall(call)) = res.pipeline.stages.iter().find(|stage| matches!(stage, Stage::FunctionCall(_))) {
+        let name = call.name.clone();
+        bail!("{} can't run in the background, only external commands can", name);
+    }
+    let command = res.pipeline.describe();
+    let children: Vec<Child> = res.pipeline.start(ctx, false)?.into_iter().filter_map(|stage| match stage {
+        SpawnedStage::Child(child) => Some(child),
+        _ => None
+    }).collect();
+    if let Some(child) = children.last() {
+        ctx.set_var(String::from("!"), Variable::U32(child.id()));
+        ctx.add_job(command, children);
+    }
+    Ok(ExecResult {
+        code: Some(0),
+        ..Default::default()
+    })
+}
+
+impl ExecExpression for FunctionDefinitionExpression {
+    fn exec(&mut self, ctx: &mut Context) -> Result<ExecResult> {
+        if ctx.is_unwinding() { return Ok(ExecResult::default()) }
+        ctx.set_func(self.name.clone(), self.clone());
+        Ok(ExecResult::default())
+    }
+}
+
+impl ExecExpression for ReturnExpression {
+    fn exec(&mut self, ctx: &mut Context) -> Result<ExecResult> {
+        if ctx.is_unwinding() { return Ok(ExecResult::default()) }
+        let val = match self.value.get(ctx)? {
+            Variable::Array(arr) if arr.is_empty() => Variable::I32(ctx.get_last_exit_code().unwrap_or(0)),
+            val => val
+        };
+        ctx.return_value = Some(val);
+        Ok(ExecResult::default())
+    }
+}
+
 impl ExecExpression for BreakExpression {
     fn exec(self: &mut BreakExpression, ctx: &mut Context) -> Result<ExecResult> {
         if ctx.break_num > 0 { ctx.break_num -= 1; return Ok(ExecResult::default()) }
@@ -141,7 +628,18 @@ impl ExecExpression for BreakExpression {
     }
 }
 
+impl ExecExpression for ContinueExpression {
+    fn exec(self: &mut ContinueExpression, ctx: &mut Context) -> Result<ExecResult> {
+        if ctx.is_unwinding() { return Ok(ExecResult::default()) }
+        let val = self.num.get(ctx)?.to_string();
+        let num: u16 = if !val.is_empty() { val.parse()? } else { 1 };
+        ctx.continue_num = if num == 0 { 1 } else { num };
+        Ok(ExecResult::default())
+    }
+}
+
 impl ExecExpression for WhileExpression {
+    #[allow(clippy::unwrap_or_default)]
     fn exec(self: &mut WhileExpression, ctx: &mut Context) -> Result<ExecResult> {
         if ctx.break_num > 0 { ctx.break_num -= 1; return Ok(ExecResult::default()) }
         ctx.add_scope();
@@ -160,10 +658,7 @@ impl ExecExpression for WhileExpression {
                 res = None;
                 break;
             }
-            if ctx.break_num > 0 {
-                ctx.break_num -= 1;
-                break;
-            }
+            if should_stop(ctx) { break }
         }
         ctx.pop_scope();
 
@@ -171,7 +666,22 @@ impl ExecExpression for WhileExpression {
     }
 }
 
+/// Handles break, continue and return statements after an iteration. Returns true if the loop should stop
+fn should_stop(ctx: &mut Context) -> bool {
+    if ctx.break_num > 0 {
+        ctx.break_num -= 1;
+        return true;
+    }
+    if ctx.continue_num > 0 {
+        // continue the next iteration of this loop, or break out of it to continue an outer loop
+        ctx.continue_num -= 1;
+        if ctx.continue_num > 0 { return true }
+    }
+    ctx.return_value.is_some()
+}
+
 impl ExecExpression for ForExpression {
+    #[allow(clippy::unwrap_or_default)]
     fn exec<'a>(&mut self, ctx: &mut Context) -> Result<ExecResult> {
         if ctx.break_num > 0 { ctx.break_num -= 1; return Ok(ExecResult::default()) }
         let arg_value = self.arg_value.get(ctx)?;
@@ -184,7 +694,6 @@ impl ExecExpression for ForExpression {
             }
         };
         let mut res: Option<ExecResult> = None;
-        let list = self.list.get(ctx)?;
 
         fn process(i: usize, val: Variable, ctx: &mut Context, arg_key: &Option<Variable>, arg_value: &Variable) -> Result<()> {
             ctx.add_scope();
@@ -195,10 +704,53 @@ impl ExecExpression for ForExpression {
             Ok(())
         }
 
+        let list = match &mut self.list {
+            ForValue::Value(list) => list.get(ctx)?,
+            ForValue::Range { start, end, step, inclusive } => {
+                let start = get_range_bound(start, ctx)?;
+                let end = get_range_bound(end, ctx)?;
+                let step = match step {
+                    Some(step) => get_range_bound(step, ctx)?,
+                    None => 1
+                };
+                if step <= 0 { bail!("Range step must be positive, got {}", step) }
+                let unsigned = start >= 0 && end >= 0;
+                let ascending = start <= end;
+                let mut current = start;
+                let mut i = 0;
+                loop {
+                    let finished = match (ascending, *inclusive) {
+                        (true, true) => current > end,
+                        (true, false) => current >= end,
+                        (false, true) => current < end,
+                        (false, false) => current <= end
+                    };
+                    if finished { break }
+                    let val = if unsigned { Variable::U64(current as u64) } else { Variable::I64(current) };
+                    process(i, val, ctx, &arg_key, &arg_value)?;
+                    if let Some(res) = res {
+                        res.exec(ctx)?;
+                    }
+                    res = Some(self.contents.exec(ctx)?);
+                    ctx.pop_scope();
+                    i += 1;
+                    if should_stop(ctx) { break }
+                    current = match if ascending { current.checked_add(step) } else { current.checked_sub(step) } {
+                        Some(next) => next,
+                        None => break
+                    };
+                }
+                if i == 0 {
+                    res = Some(self.else_contents.exec(ctx)?);
+                }
+                return Ok(res.unwrap_or_default());
+            }
+        };
+
         match list {
             Variable::Array(arr) => {
                 if arr.is_empty() {
-                    self.else_contents.exec(ctx)?;
+                    res = Some(self.else_contents.exec(ctx)?);
                 } else {
                     for (i, val) in arr.iter().enumerate() {
                         process(i, val.clone(), ctx, &arg_key, &arg_value)?;
@@ -207,16 +759,13 @@ impl ExecExpression for ForExpression {
                         }
                         res = Some(self.contents.exec(ctx)?);
                         ctx.pop_scope();
-                        if ctx.break_num > 0 {
-                            ctx.break_num -= 1;
-                            break;
-                        }
+                        if should_stop(ctx) { break }
                     }
                 }
             },
             Variable::String(str) => {
                 if str.is_empty() {
-                    self.else_contents.exec(ctx)?;
+                    res = Some(self.else_contents.exec(ctx)?);
                 } else {
                     for (i, char) in str.chars().enumerate() {
                         process(i, Variable::String(char.to_string()), ctx, &arg_key, &arg_value)?;
@@ -225,10 +774,7 @@ impl ExecExpression for ForExpression {
                         }
                         res = Some(self.contents.exec(ctx)?);
                         ctx.pop_scope();
-                        if ctx.break_num > 0 {
-                            ctx.break_num -= 1;
-                            break;
-                        }
+                        if should_stop(ctx) { break }
                     }
                 }
             },
@@ -239,9 +785,15 @@ impl ExecExpression for ForExpression {
     }
 }
 
+/// Evaluates a bound or step of a for range
+fn get_range_bound(val: &mut Value, ctx: &mut Context) -> Result<i64> {
+    let val = val.get(ctx)?.to_string();
+    val.trim().parse().with_context(|| format!("Invalid range bound {}", val))
+}
+
 impl ExecExpression for IfExpression {
     fn exec(self: &mut IfExpression, ctx: &mut Context) -> Result<ExecResult> {
-        if ctx.break_num > 0 { return Ok(ExecResult::default()) }
+        if ctx.is_unwinding() { return Ok(ExecResult::default()) }
         let condition = self.condition.exec(ctx)?;
         ctx.add_scope();
         let condition_result = condition.exec(ctx)?;
@@ -259,85 +811,381 @@ impl ExecExpression for IfExpression {
 
 impl ExecExpression for LetExpression {
     fn exec(self: &mut LetExpression, ctx: &mut Context) -> Result<ExecResult> {
-        if ctx.break_num > 0 { return Ok(ExecResult::default()) }
+        if ctx.is_unwinding() { return Ok(ExecResult::default()) }
         let key = self.key.get(ctx)?;
-        let val = self.value.get(ctx)?;
-        ctx.set_var(key.to_string(), val);
+        let mut val = self.value.get(ctx)?;
+        if let Some(vartype) = &self.vartype {
+            val = convert_to_type(&key.to_string(), vartype, val)?;
+        }
+        if self.index.is_empty() {
+            ctx.set_var(key.to_string(), val);
+            return Ok(ExecResult::default());
+        }
+        let mut index = Vec::new();
+        for idx in &mut self.index {
+            index.push(match idx {
+                IndexValue::Key(key) => Some(key.get(ctx)?),
+                IndexValue::Append => None,
+                IndexValue::Range { .. } => bail!("Cannot assign to a slice")
+            });
+        }
+        if ctx.get_var(&key.to_string()).is_none() {
+            // let map[key] = value creates a map, let arr[+] = value an array
+            let var = if index[0].is_some() { Variable::HMap(HashMap::new()) } else { Variable::Array(Vec::new()) };
+            ctx.set_var(key.to_string(), var);
+        }
+        let mut target = ctx.get_var(&key.to_string()).unwrap();
+        let last = index.pop().unwrap();
+        for idx in &index {
+            target = match idx {
+                Some(idx) => target.index_mut(idx)?,
+                None => bail!("[+] can only be used as the last index")
+            };
+        }
+        match (last, target) {
+            (None, Variable::Array(arr)) => arr.push(val),
+            (None, target) => bail!("Cannot append to {}, it isn't an array", target),
+            (Some(idx), target) => *target.index_mut(&idx)? = val
+        }
         Ok(ExecResult::default())
     }
 }
 
 impl ExecExpression for Vec<CommandValue> {
     fn exec(self: &mut Vec<CommandValue>, ctx: &mut Context) -> Result<ExecResult> {
-        if ctx.break_num > 0 { return Ok(ExecResult::default()) }
+        if ctx.is_unwinding() { return Ok(ExecResult::default()) }
         if self.is_empty() { bail!("Command with 0 length"); }
-        let first = self.get_mut(0).unwrap();
-        let command_name = first.get(ctx)?.to_string();
-        let mut cmd = Command::new(command_name);
-        for value in &mut self[1..] {
-            cmd.arg(value.get(ctx)?.to_string());
-        }
-        let overrides = ctx.get_overrides()?;
-        if let Some(stdout) = overrides.stdout { cmd.stdout(stdout); }
-        if let Some(stderr) = overrides.stderr { cmd.stderr(stderr); }
-        if let Some(stdin) = overrides.stdin { cmd.stdin(stdin); }
-        Ok(ExecResult {
-            commands: vec![cmd]
-        })
+        let fd_count = ctx.scopes.last().unwrap().fd.len();
+        let words = get_words(self, ctx);
+        // process substitutions in the arguments stay open until the command is started, the pipeline closes them
+        let mut pipeline = Pipeline { stages: Vec::new(), fds: ctx.scopes.last_mut().unwrap().fd.split_off(fd_count) };
+        let (command_name, args) = words?;
+        let target = match ctx.get_func(&command_name) {
+            Some(AnyFunction::UserDefined(func)) => FunctionCallTarget::UserDefined(func.clone()),
+            Some(AnyFunction::Native(func)) => FunctionCallTarget::Native(func.func),
+            None => {
+                pipeline.stages.push(Stage::Command(build_command(command_name, args, &pipeline.fds, ctx)?));
+                return Ok(ExecResult { pipeline, ..Default::default() });
+            }
+        };
+        let call = FunctionCall {
+            name: command_name,
+            target,
+            args,
+            overrides: ctx.get_overrides()?
+        };
+        pipeline.stages.push(Stage::FunctionCall(call));
+        Ok(ExecResult { pipeline, ..Default::default() })
     }
 }
 
-impl ExecExpression for RedirectTargetExpression {
-    fn exec(self: &mut RedirectTargetExpression, ctx: &mut Context) -> Result<ExecResult> {
-        if ctx.break_num > 0 { return Ok(ExecResult::default()) }
-        let (reader, writer) = os_pipe::pipe()?;
+/// Name and arguments of a command
+fn get_words(values: &mut [CommandValue], ctx: &mut Context) -> Result<(String, Vec<Variable>)> {
+    let command_name = values[0].get(ctx)?.to_string();
+    let mut args = Vec::new();
+    for value in &mut values[1..] {
+        match value {
+            // complete -f $name() keeps the name of the function to call it when completing
+            CommandValue::Value(Value::ValueFunction(call)) if command_name == "complete" && call.args.is_empty()
+                && args.last().is_some_and(|arg: &Variable| arg.to_string() == "-f") => {
+                args.push(Variable::String(call.name[1..].to_string()));
+            },
+            CommandValue::Value(value) if is_splat(value) => splat(value.get(ctx)?, &mut args),
+            CommandValue::Value(value) => match get_glob_pattern(value, ctx)? {
+                Some(pattern) => args.extend(glob::expand(&pattern).into_iter().map(Variable::String)),
+                None => args.push(value.get(ctx)?)
+            },
+            CommandValue::Var(_, _) => bail!("Broken executor")
+        }
+    }
+    Ok((command_name, args))
+}
 
-        ctx.add_scope();
-        ctx.scopes.last_mut().unwrap().stdout_override = Some(WriterOverride::Pipe(writer));
-        let mut src = self.source.exec(ctx)?;
-        ctx.pop_scope();
-        ctx.add_scope();
-        ctx.scopes.last_mut().unwrap().stdin_override = Some(ReaderOverride::Pipe(reader));
-        let target = self.target.exec(ctx)?;
-        ctx.pop_scope();
-        src.merge(target);
+/// @name (optionally indexed) and @(...) pass each element as a separate argument, unlike $name
+fn is_splat(value: &Value) -> bool {
+    match value {
+        Value::ArrayVariable(_) | Value::ArrayExpressions(_) => true,
+        Value::Index(value, _) => is_splat(value),
+        _ => false
+    }
+}
 
-        Ok(src)
+/// Adds the elements of an array as arguments, flattening nested arrays
+fn splat(var: Variable, args: &mut Vec<Variable>) {
+    match var {
+        Variable::Array(vars) => for var in vars {
+            splat(var, args);
+        },
+        var => args.push(var)
     }
 }
 
-impl ExecExpression for FileTargetExpression {
-    fn exec(self: &mut FileTargetExpression, ctx: &mut Context) -> Result<ExecResult> {
-        if ctx.break_num > 0 { return Ok(ExecResult::default()) }
-        let src = &mut self.source;
-        let target = self.target.get(ctx)?;
+/// Pattern of an argument with unquoted glob parts. The other parts are escaped, so they only match themselves
+fn get_glob_pattern(value: &mut Value, ctx: &mut Context) -> Result<Option<String>> {
+    match value {
+        Value::Glob(pattern) => Ok(Some(pattern.clone())),
+        Value::Concat(parts) if parts.iter().any(|part| matches!(part, Value::Glob(_))) => {
+            let mut pattern = String::new();
+            for part in parts {
+                match part {
+                    Value::Glob(str) => pattern += str,
+                    part => pattern += &glob::escape(&part.get(ctx)?.to_string())
+                }
+            }
+            Ok(Some(pattern))
+        },
+        _ => Ok(None)
+    }
+}
 
-        ctx.add_scope();
+/// Builds an external command with the current overrides applied. The process substitution descriptors are only
+/// inherited by this command
+fn build_command(command_name: String, args: Vec<Variable>, fds: &[usize], ctx: &Context) -> Result<Command> {
+    let mut cmd = Command::new(command_name);
+    for arg in args {
+        cmd.arg(arg.to_string());
+    }
+    cmd.envs(ctx.exports.iter().map(|(key, value)| (key, value.to_string())));
+    let overrides = ctx.get_overrides()?;
+    let mut closed = Vec::new();
+    if let Some(stdout) = overrides.stdout { set_output(&mut cmd, 1, stdout, &mut closed); }
+    if let Some(stderr) = overrides.stderr { set_output(&mut cmd, 2, stderr, &mut closed); }
+    if let Some(stdin) = overrides.stdin { cmd.stdin(stdin); }
+    let inherited: Vec<i32> = fds.iter().map(|fd| *fd as i32).collect();
+    if !closed.is_empty() || !inherited.is_empty() {
+        unsafe {
+            cmd.pre_exec(move || {
+                for fd in &closed {
+                    libc::close(*fd);
+                }
+                inherit(&inherited);
+                Ok(())
+            });
+        }
+    }
+    Ok(cmd)
+}
 
-        let file = File::create(target.to_string())?;
-        ctx.scopes.last_mut().unwrap().stdout_override = Some(WriterOverride::File(file));
+/// Connects stdout (1) or stderr (2) of a command. Closed descriptors are added to closed, to be closed in the command
+fn set_output(cmd: &mut Command, fd: i32, writer: WriterOverride, closed: &mut Vec<i32>) {
+    let stdio: Stdio = match writer {
+        WriterOverride::Pipe(pipe) => pipe.into(),
+        WriterOverride::File(file) => file.into(),
+        WriterOverride::Closed => {
+            closed.push(fd);
+            return;
+        }
+    };
+    if fd == 1 { cmd.stdout(stdio); } else { cmd.stderr(stdio); }
+}
 
-        let src = match src {
-            Some(expr) => expr.exec(ctx)?,
-            None => {
-                bail!("Redirect without target file");
+impl ExecExpression for PipelineExpression {
+    fn exec(self: &mut PipelineExpression, ctx: &mut Context) -> Result<ExecResult> {
+        if ctx.is_unwinding() { return Ok(ExecResult::default()) }
+        let mut res = ExecResult::default();
+        let mut input: Option<PipeReader> = None;
+        let last = self.commands.len() - 1;
+        for (i, command) in self.commands.iter_mut().enumerate() {
+            let output = if i < last { Some(os_pipe::pipe()?) } else { None };
+            ctx.add_scope();
+            let scope = ctx.scopes.last_mut().unwrap();
+            scope.stdin_override = input.take().map(ReaderOverride::Pipe);
+            if let Some((reader, writer)) = output {
+                scope.stdout_override = Some(WriterOverride::Pipe(writer));
+                input = Some(reader);
             }
+            let stage = command.exec(ctx);
+            ctx.pop_scope();
+            res.merge(stage?);
+        }
+        Ok(res)
+    }
+}
+
+impl FileTargetExpression {
+    /// Applies the redirect to the current scope. Redirects are applied left to right, and the parser nests them with
+    /// the leftmost one innermost, so inner ones go first
+    fn redirect(&mut self, ctx: &mut Context) -> Result<()> {
+        if let Some(Expression::FileTargetExpression(inner)) = self.source.as_deref_mut() {
+            inner.redirect(ctx)?;
+        }
+        let writer = match self.mode {
+            RedirectMode::Write => {
+                let target = self.target.get(ctx)?.to_string();
+                WriterOverride::File(File::create(&target).with_context(|| format!("Couldn't open {} to write", target))?)
+            },
+            RedirectMode::Append => {
+                let target = self.target.get(ctx)?.to_string();
+                let file = File::options().create(true).append(true).open(&target)
+                    .with_context(|| format!("Couldn't open {} to append", target))?;
+                WriterOverride::File(file)
+            },
+            RedirectMode::Duplicate(fd) => {
+                let overrides = ctx.get_overrides()?;
+                match fd {
+                    1 => overrides.stdout.unwrap_or(WriterOverride::Pipe(os_pipe::dup_stdout()?)),
+                    _ => overrides.stderr.unwrap_or(WriterOverride::Pipe(os_pipe::dup_stderr()?))
+                }
+            },
+            RedirectMode::Close => WriterOverride::Closed
         };
+        let scope = ctx.scopes.last_mut().unwrap();
+        match self.fd {
+            1 => scope.stdout_override = Some(writer),
+            _ => scope.stderr_override = Some(writer)
+        }
+        Ok(())
+    }
 
+    /// Runs the redirected expression, below all the nested redirects
+    fn exec_source(&mut self, ctx: &mut Context) -> Result<ExecResult> {
+        match self.source.as_deref_mut() {
+            Some(Expression::FileTargetExpression(inner)) => inner.exec_source(ctx),
+            Some(expr) => expr.exec(ctx),
+            None => bail!("Redirect without source command")
+        }
+    }
+}
+
+impl ExecExpression for FileTargetExpression {
+    fn exec(self: &mut FileTargetExpression, ctx: &mut Context) -> Result<ExecResult> {
+        if ctx.is_unwinding() { return Ok(ExecResult::default()) }
+        ctx.add_scope();
+        let res = self.redirect(ctx).and_then(|_| self.exec_source(ctx));
         ctx.pop_scope();
-        Ok(src)
+        res
+    }
+}
+
+/// Expands the body of a here-document or the word of a ${...} operator like a double quoted string
+fn expand_string(str: &str, ctx: &mut Context) -> Result<String> {
+    Ok(parse_quoted(str)?.get(ctx)?.to_string())
+}
+
+/// Applies a ${...} operator to a variable
+fn expand_parameter(name: &str, operator: &ParameterOperator, ctx: &mut Context) -> Result<Variable> {
+    let value = ctx.get_var(name).map(|var| var.clone());
+    let set = value.as_ref().is_some_and(|value| !value.to_string().is_empty());
+    let str = value.as_ref().map(|value| value.to_string()).unwrap_or_default();
+    let chars: Vec<char> = str.chars().collect();
+    let matches = |pattern: &str, part: &[char]| glob::matches(&pattern.chars().collect::<Vec<char>>(), part);
+    let res = match operator {
+        ParameterOperator::Default(_) | ParameterOperator::Assign(_) | ParameterOperator::Error(_) if set => return Ok(value.unwrap()),
+        ParameterOperator::Default(word) => expand_string(word, ctx)?,
+        ParameterOperator::Assign(word) => {
+            let word = expand_string(word, ctx)?;
+            ctx.set_var(name.to_string(), Variable::String(word.clone()));
+            word
+        },
+        ParameterOperator::Error(word) => {
+            let message = expand_string(word, ctx)?;
+            bail!("{}: {}", name, if message.is_empty() { "parameter null or not set" } else { &message })
+        },
+        ParameterOperator::Length => return Ok(Variable::I64(match value {
+            Some(Variable::Array(arr)) => arr.len(),
+            Some(Variable::HMap(map)) => map.len(),
+            _ => chars.len()
+        } as i64)),
+        ParameterOperator::RemovePrefix(pattern, longest) => {
+            let pattern = expand_string(pattern, ctx)?;
+            let mut lengths: Vec<usize> = (0..=chars.len()).collect();
+            if *longest { lengths.reverse(); }
+            match lengths.into_iter().find(|len| matches(&pattern, &chars[..*len])) {
+                Some(len) => chars[len..].iter().collect(),
+                None => str
+            }
+        },
+        ParameterOperator::RemoveSuffix(pattern, longest) => {
+            let pattern = expand_string(pattern, ctx)?;
+            let mut starts: Vec<usize> = (0..=chars.len()).collect();
+            if !*longest { starts.reverse(); }
+            match starts.into_iter().find(|start| matches(&pattern, &chars[*start..])) {
+                Some(start) => chars[..start].iter().collect(),
+                None => str
+            }
+        },
+        ParameterOperator::Replace { pattern, replacement, all } => {
+            let pattern = expand_string(pattern, ctx)?;
+            let replacement = expand_string(replacement, ctx)?;
+            if pattern.is_empty() {
+                return Ok(Variable::String(str));
+            }
+            let mut out = String::new();
+            let mut i = 0;
+            let mut replaced = false;
+            while i < chars.len() {
+                // the longest match starting at i is replaced
+                let end = if replaced && !*all { None } else { (i + 1..=chars.len()).rev().find(|end| matches(&pattern, &chars[i..*end])) };
+                match end {
+                    Some(end) => {
+                        out += &replacement;
+                        i = end;
+                        replaced = true;
+                    },
+                    None => {
+                        out.push(chars[i]);
+                        i += 1;
+                    }
+                }
+            }
+            out
+        },
+        ParameterOperator::Upper(all) => change_case(&chars, *all, |letter| letter.to_uppercase().collect()),
+        ParameterOperator::Lower(all) => change_case(&chars, *all, |letter| letter.to_lowercase().collect())
+    };
+    Ok(Variable::String(res))
+}
+
+/// Converts the first or all letters
+fn change_case(chars: &[char], all: bool, convert: fn(&char) -> String) -> String {
+    let count = if all { chars.len() } else { 1 };
+    chars.iter().enumerate().map(|(x, letter)| if x < count { convert(letter) } else { letter.to_string() }).collect()
+}
+
+/// Home directory for ~ or ~user. Unknown users are kept as written
+fn expand_tilde(user: &str, ctx: &Context) -> Result<String> {
+    if user.is_empty() {
+        return match ctx.exports.get("HOME") {
+            Some(home) => Ok(home.to_string()),
+            None => std::env::var("HOME").with_context(|| "HOME not set")
+        };
+    }
+    let name = CString::new(user)?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Ok(format!("~{}", user));
     }
+    Ok(unsafe { CStr::from_ptr((*passwd).pw_dir) }.to_string_lossy().to_string())
+}
+
+/// Feeds a string to stdin through a pipe, written from a thread so it can be larger than the pipe buffer
+fn pipe_input(content: String) -> Result<ReaderOverride> {
+    let (reader, mut writer) = os_pipe::pipe()?;
+    thread::spawn(move || {
+        // the reading side may exit without reading everything
+        let _ = writer.write_all(content.as_bytes());
+    });
+    Ok(ReaderOverride::Pipe(reader))
 }
 
 impl ExecExpression for FileSourceExpression {
     fn exec(self: &mut FileSourceExpression, ctx: &mut Context) -> Result<ExecResult> {
-        if ctx.break_num > 0 { return Ok(ExecResult::default()) }
-        let source = self.source.get(ctx)?.to_string();
-        let source = File::open(source).with_context(|| "Couldn't open file to read")?;
+        if ctx.is_unwinding() { return Ok(ExecResult::default()) }
+        let source = match self.mode {
+            SourceMode::File => {
+                let source = self.source.get(ctx)?.to_string();
+                ReaderOverride::File(File::open(source).with_context(|| "Couldn't open file to read")?)
+            },
+            SourceMode::HereDoc { expand } => {
+                let content = self.source.get(ctx)?.to_string();
+                pipe_input(if expand { expand_string(&content, ctx)? } else { content })?
+            },
+            SourceMode::HereString => pipe_input(self.source.get(ctx)?.to_string() + "\n")?
+        };
         let target = &mut self.target;
 
         ctx.add_scope();
-        ctx.scopes.last_mut().unwrap().stdin_override = Some(ReaderOverride::File(source));
+        ctx.scopes.last_mut().unwrap().stdin_override = Some(source);
         let target = match target {
             Some(expr) => expr.exec(ctx)?,
             None => {
@@ -351,15 +1199,16 @@ impl ExecExpression for FileSourceExpression {
 }
 
 impl ExecExpression for Vec<Expression> {
+    #[allow(clippy::unwrap_or_default)]
     fn exec(self: &mut Vec<Expression>, ctx: &mut Context) -> Result<ExecResult> {
-        if ctx.break_num > 0 { return Ok(ExecResult::default()) }
+        if ctx.is_unwinding() { return Ok(ExecResult::default()) }
         let mut last: Option<ExecResult> = None;
         for expr in self {
             if let Some(last) = last {
                 last.exec(ctx)?;
             }
             last = Some(expr.exec(ctx)?);
-            if ctx.break_num > 0 { return Ok(last.unwrap()) }
+            if ctx.is_unwinding() { return Ok(last.unwrap()) }
         }
         Ok(last.unwrap_or(ExecResult::default()))
     }
@@ -367,7 +1216,7 @@ impl ExecExpression for Vec<Expression> {
 
 impl ExecExpression for OrExpression {
     fn exec(self: &mut OrExpression, ctx: &mut Context) -> Result<ExecResult> {
-        if ctx.break_num > 0 { return Ok(ExecResult::default()) }
+        if ctx.is_unwinding() { return Ok(ExecResult::default()) }
         let first = self.first.exec(ctx)?;
         let code = first.exec(ctx)?;
         let code = code.unwrap_or(1);
@@ -379,9 +1228,18 @@ impl ExecExpression for OrExpression {
     }
 }
 
+impl ExecExpression for NotExpression {
+    fn exec(self: &mut NotExpression, ctx: &mut Context) -> Result<ExecResult> {
+        if ctx.is_unwinding() { return Ok(ExecResult::default()) }
+        let mut res = self.expr.exec(ctx)?;
+        res.negate = !res.negate;
+        Ok(res)
+    }
+}
+
 impl ExecExpression for AndExpression {
     fn exec(self: &mut AndExpression, ctx: &mut Context) -> Result<ExecResult> {
-        if ctx.break_num > 0 { return Ok(ExecResult::default()) }
+        if ctx.is_unwinding() { return Ok(ExecResult::default()) }
         let first = self.first.exec(ctx)?;
         let code = first.exec(ctx)?;
         let code = code.unwrap_or(1);
@@ -394,10 +1252,18 @@ impl ExecExpression for AndExpression {
 }
 
 pub fn exec_tree(tree: Vec<Expression>, ctx: &mut Context) -> Result<()> {
+    let depth = ctx.scopes.len();
     for mut expression in tree {
-        let cmd = expression.exec(ctx)?;
-        cmd.exec(ctx)?;
+        let res = expression.exec(ctx).and_then(|cmd| cmd.exec(ctx));
+        // redirects of an expression that failed don't apply to the next ones
+        ctx.restore_scopes(depth);
+        res?;
         if ctx.break_num > 0 { bail!("Too many break statements") }
+        if ctx.continue_num > 0 {
+            ctx.continue_num = 0;
+            bail!("Too many continue statements")
+        }
+        if ctx.return_value.take().is_some() { bail!("Return outside of a function") }
     }
     Ok(())
 }
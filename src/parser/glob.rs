@@ -0,0 +1,253 @@
+use std::fs;
+use std::path::Path;
+
+/// Whether an unquoted part of a word has to be expanded
+pub fn is_pattern(str: &str) -> bool {
+    str.contains(['*', '?', '[', '{'])
+}
+
+/// Escapes quoted or substituted parts of a word, so they only match themselves
+pub fn escape(str: &str) -> String {
+    let mut out = String::new();
+    for letter in str.chars() {
+        if matches!(letter, '*' | '?' | '[' | ']' | '{' | '}' | ',' | '\\') {
+            out.push('\\');
+        }
+        out.push(letter);
+    }
+    out
+}
+
+fn unescape(str: &str) -> String {
+    let mut out = String::new();
+    let mut chars = str.chars();
+    while let Some(letter) = chars.next() {
+        match letter {
+            '\\' => out.extend(chars.next()),
+            letter => out.push(letter)
+        }
+    }
+    out
+}
+
+/// Expands {a,b} alternatives, then matches *, ?, [...] and ** against the filesystem.
+/// Patterns without any matching file are kept as they are
+pub fn expand(pattern: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    for pattern in expand_braces(pattern) {
+        let found = if has_wildcards(&pattern) { glob(&pattern) } else { Vec::new() };
+        if found.is_empty() {
+            out.push(unescape(&pattern));
+        } else {
+            out.extend(found);
+        }
+    }
+    out
+}
+
+fn has_wildcards(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(letter) = chars.next() {
+        match letter {
+            '\\' => { chars.next(); },
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Finds the } closing the brace at start, with the positions of its top level commas
+fn find_brace_end(chars: &[char], start: usize) -> Option<(usize, Vec<usize>)> {
+    let mut lvl = 0;
+    let mut commas = Vec::new();
+    let mut i = start;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '{' => lvl += 1,
+            '}' => {
+                lvl -= 1;
+                if lvl == 0 { return Some((i, commas)) }
+            },
+            ',' if lvl == 1 => commas.push(i),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '{' => if let Some((end, commas)) = find_brace_end(&chars, i) {
+                // {} and {a} aren't expanded, like in bash
+                if !commas.is_empty() {
+                    let prefix: String = chars[..i].iter().collect();
+                    let suffix: String = chars[end + 1..].iter().collect();
+                    let mut out = Vec::new();
+                    let mut start = i + 1;
+                    for separator in commas.into_iter().chain([end]) {
+                        let alternative: String = chars[start..separator].iter().collect();
+                        out.extend(expand_braces(&format!("{}{}{}", prefix, alternative, suffix)));
+                        start = separator + 1;
+                    }
+                    return out;
+                }
+            },
+            _ => {}
+        }
+        i += 1;
+    }
+    vec![pattern.to_string()]
+}
+
+/// Matches a [...] class against a letter, returning whether it matched and the length of the class including ].
+/// None if the class isn't closed, then [ is a literal
+fn match_class(pattern: &[char], letter: char) -> Option<(bool, usize)> {
+    let negate = matches!(pattern.first(), Some('!' | '^'));
+    let mut i = if negate { 1 } else { 0 };
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let mut start = *pattern.get(i)?;
+        if start == ']' && !first {
+            return Some((matched != negate, i + 1));
+        }
+        first = false;
+        if start == '\\' {
+            i += 1;
+            start = *pattern.get(i)?;
+        }
+        i += 1;
+        if pattern.get(i) == Some(&'-') && pattern.get(i + 1).is_some_and(|end| *end != ']') {
+            let mut end = pattern[i + 1];
+            i += 2;
+            if end == '\\' {
+                end = *pattern.get(i)?;
+                i += 1;
+            }
+            if start <= letter && letter <= end { matched = true; }
+        } else if start == letter {
+            matched = true;
+        }
+    }
+}
+
+/// Matches a single path component, or a string in ${name#pattern}. On a mismatch only the last * takes one more
+/// letter, so the time stays linear in the number of *s
+pub fn matches(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // pattern position after the last *, and the letter of the name it matched up to
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if let Some(len) = match_one(&pattern[p..], name[n]) {
+            p += len;
+            n += 1;
+        } else if pattern.get(p) == Some(&'*') {
+            p += 1;
+            star = Some((p, n));
+        } else if let Some((after, matched)) = star {
+            p = after;
+            n = matched + 1;
+            star = Some((after, n));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|letter| *letter == '*')
+}
+
+/// Length of the pattern element at the start of the pattern if it matches the letter. * is handled by matches
+fn match_one(pattern: &[char], letter: char) -> Option<usize> {
+    match pattern.first()? {
+        '*' => None,
+        '?' => Some(1),
+        '[' => match match_class(&pattern[1..], letter) {
+            None => (letter == '[').then_some(1),
+            Some((matched, len)) => matched.then_some(len + 1)
+        },
+        '\\' if pattern.len() > 1 => (pattern[1] == letter).then_some(2),
+        first => (*first == letter).then_some(1)
+    }
+}
+
+/// Sorted names in a directory, "" is the working directory
+fn read_names(dir: &str) -> Vec<(String, bool)> {
+    let entries = match fs::read_dir(if dir.is_empty() { "." } else { dir }) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new()
+    };
+    let mut names: Vec<(String, bool)> = entries.flatten()
+        .map(|entry| (entry.file_name().to_string_lossy().to_string(), entry.file_type().is_ok_and(|kind| kind.is_dir())))
+        .collect();
+    names.sort();
+    names
+}
+
+/// Adds every file and directory below dir, without hidden ones. Symlinked directories aren't followed
+fn walk(dir: &str, dirs_only: bool, out: &mut Vec<String>) {
+    for (name, is_dir) in read_names(dir) {
+        if name.starts_with('.') { continue }
+        let path = format!("{}{}", dir, name);
+        if is_dir {
+            out.push(if dirs_only { format!("{}/", path) } else { path.clone() });
+            walk(&format!("{}/", path), dirs_only, out);
+        } else if !dirs_only {
+            out.push(path);
+        }
+    }
+}
+
+/// Paths matching the pattern, component by component. Every path is kept as a prefix ending with / (or "" for the
+/// working directory) until the last component
+fn glob(pattern: &str) -> Vec<String> {
+    let (mut paths, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => (vec![String::from("/")], rest),
+        None => (vec![String::new()], pattern)
+    };
+    let components: Vec<&str> = rest.split('/').collect();
+    for (x, component) in components.iter().enumerate() {
+        let last = x == components.len() - 1;
+        let mut next = Vec::new();
+        for path in paths {
+            if component.is_empty() {
+                // a trailing / only keeps directories, which all paths before it are
+                next.push(path);
+            } else if *component == "**" {
+                // ** matches any number of directories, or every file if it's the last component
+                if !last { next.push(path.clone()); }
+                walk(&path, !last, &mut next);
+            } else if !has_wildcards(component) {
+                let candidate = format!("{}{}", path, unescape(component));
+                if last && fs::symlink_metadata(&candidate).is_ok() {
+                    next.push(candidate);
+                } else if !last && Path::new(&candidate).is_dir() {
+                    next.push(candidate + "/");
+                }
+            } else {
+                let chars: Vec<char> = component.chars().collect();
+                // hidden files are only matched by patterns starting with a dot
+                let hidden = component.starts_with('.') || component.starts_with("\\.");
+                for (name, _) in read_names(&path) {
+                    if name.starts_with('.') && !hidden { continue }
+                    if !matches(&chars, &name.chars().collect::<Vec<char>>()) { continue }
+                    let candidate = format!("{}{}", path, name);
+                    if last {
+                        next.push(candidate);
+                    } else if Path::new(&candidate).is_dir() {
+                        next.push(candidate + "/");
+                    }
+                }
+            }
+        }
+        paths = next;
+    }
+    paths.sort();
+    paths.dedup();
+    paths
+}
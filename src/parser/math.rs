@@ -0,0 +1,425 @@
+use crate::parser::vars::{Context, Variable};
+use std::cmp::Ordering;
+use anyhow::{bail, Context as AnyhowContext, Result};
+
+#[derive(Debug, Clone)]
+enum MathToken {
+    Number(Variable),
+    Variable(String),
+    Plus,
+    Minus,
+    Multiply,
+    Divide,
+    Remainder,
+    Power,
+    ParenthesisStart,
+    ParenthesisEnd
+}
+
+impl MathToken {
+    fn to_str(&self) -> String {
+        match self {
+            MathToken::Number(num) => num.to_string(),
+            MathToken::Variable(name) => name.clone(),
+            MathToken::Plus => "+".to_string(),
+            MathToken::Minus => "-".to_string(),
+            MathToken::Multiply => "*".to_string(),
+            MathToken::Divide => "/".to_string(),
+            MathToken::Remainder => "%".to_string(),
+            MathToken::Power => "**".to_string(),
+            MathToken::ParenthesisStart => "(".to_string(),
+            MathToken::ParenthesisEnd => ")".to_string()
+        }
+    }
+}
+
+/// Numeric type of a value
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NumberKind {
+    I32,
+    I64,
+    I128,
+    U32,
+    U64,
+    U128,
+    F32,
+    F64
+}
+
+impl NumberKind {
+    fn of(var: &Variable) -> Result<NumberKind> {
+        Ok(match var {
+            Variable::I32(_) => NumberKind::I32,
+            Variable::I64(_) => NumberKind::I64,
+            Variable::I128(_) => NumberKind::I128,
+            Variable::U32(_) => NumberKind::U32,
+            Variable::U64(_) => NumberKind::U64,
+            Variable::U128(_) => NumberKind::U128,
+            Variable::F32(_) => NumberKind::F32,
+            Variable::F64(_) => NumberKind::F64,
+            var => bail!("Expected a number, got {}", var)
+        })
+    }
+
+    fn from_name(name: &str) -> Option<NumberKind> {
+        Some(match name {
+            "i32" => NumberKind::I32,
+            "i64" => NumberKind::I64,
+            "i128" => NumberKind::I128,
+            "u32" => NumberKind::U32,
+            "u64" => NumberKind::U64,
+            "u128" => NumberKind::U128,
+            "f32" => NumberKind::F32,
+            "f64" => NumberKind::F64,
+            _ => return None
+        })
+    }
+
+    fn is_float(self) -> bool {
+        matches!(self, NumberKind::F32 | NumberKind::F64)
+    }
+
+    fn is_unsigned(self) -> bool {
+        matches!(self, NumberKind::U32 | NumberKind::U64 | NumberKind::U128)
+    }
+
+    fn width(self) -> u32 {
+        match self {
+            NumberKind::I32 | NumberKind::U32 | NumberKind::F32 => 32,
+            NumberKind::I64 | NumberKind::U64 | NumberKind::F64 => 64,
+            NumberKind::I128 | NumberKind::U128 => 128
+        }
+    }
+
+    fn signed(width: u32) -> NumberKind {
+        match width {
+            32 => NumberKind::I32,
+            64 => NumberKind::I64,
+            _ => NumberKind::I128
+        }
+    }
+
+    /// Type both operands get promoted to. Mixing signed and unsigned integers results in a signed integer wide enough
+    /// for both, floats win over integers
+    fn promote(a: NumberKind, b: NumberKind) -> NumberKind {
+        if a.is_float() || b.is_float() {
+            return if a == NumberKind::F32 && b == NumberKind::F32 { NumberKind::F32 } else { NumberKind::F64 };
+        }
+        if a.is_unsigned() == b.is_unsigned() {
+            return if a.width() >= b.width() { a } else { b };
+        }
+        let (unsigned, signed) = if a.is_unsigned() { (a, b) } else { (b, a) };
+        NumberKind::signed(std::cmp::max(signed.width(), unsigned.width() * 2))
+    }
+}
+
+fn to_i128(var: &Variable) -> Result<i128> {
+    Ok(match var {
+        Variable::I32(num) => *num as i128,
+        Variable::I64(num) => *num as i128,
+        Variable::I128(num) => *num,
+        Variable::U32(num) => *num as i128,
+        Variable::U64(num) => *num as i128,
+        Variable::U128(num) => i128::try_from(*num).with_context(|| "Arithmetic overflow")?,
+        var => bail!("Expected an integer, got {}", var)
+    })
+}
+
+fn to_u128(var: &Variable) -> Result<u128> {
+    Ok(match var {
+        Variable::U32(num) => *num as u128,
+        Variable::U64(num) => *num as u128,
+        Variable::U128(num) => *num,
+        var => u128::try_from(to_i128(var)?).with_context(|| "Arithmetic overflow")?
+    })
+}
+
+fn to_f64(var: &Variable) -> Result<f64> {
+    Ok(match var {
+        Variable::F32(num) => *num as f64,
+        Variable::F64(num) => *num,
+        Variable::U128(num) => *num as f64,
+        var => to_i128(var)? as f64
+    })
+}
+
+/// Converts a value to the given numeric type, failing if it doesn't fit
+fn convert(var: &Variable, kind: NumberKind) -> Result<Variable> {
+    Ok(match kind {
+        NumberKind::I32 => Variable::I32(i32::try_from(to_i128(var)?).with_context(|| "Arithmetic overflow")?),
+        NumberKind::I64 => Variable::I64(i64::try_from(to_i128(var)?).with_context(|| "Arithmetic overflow")?),
+        NumberKind::I128 => Variable::I128(to_i128(var)?),
+        NumberKind::U32 => Variable::U32(u32::try_from(to_u128(var)?).with_context(|| "Arithmetic overflow")?),
+        NumberKind::U64 => Variable::U64(u64::try_from(to_u128(var)?).with_context(|| "Arithmetic overflow")?),
+        NumberKind::U128 => Variable::U128(to_u128(var)?),
+        NumberKind::F32 => Variable::F32(to_f64(var)? as f32),
+        NumberKind::F64 => Variable::F64(to_f64(var)?)
+    })
+}
+
+/// Parses a number literal, with an optional type suffix (like 5u32 or 2.5f32). Integers without a suffix are i64, or
+/// wider if they don't fit, floats are f64
+fn parse_number(str: &str) -> Result<Variable> {
+    let suffix_start = str.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(str.len());
+    let (num, suffix) = str.split_at(suffix_start);
+    let invalid = || format!("Invalid number {}", str);
+    Ok(match suffix {
+        "i32" => Variable::I32(num.parse().with_context(invalid)?),
+        "i64" => Variable::I64(num.parse().with_context(invalid)?),
+        "i128" => Variable::I128(num.parse().with_context(invalid)?),
+        "u32" => Variable::U32(num.parse().with_context(invalid)?),
+        "u64" => Variable::U64(num.parse().with_context(invalid)?),
+        "u128" => Variable::U128(num.parse().with_context(invalid)?),
+        "f32" => Variable::F32(num.parse().with_context(invalid)?),
+        "f64" => Variable::F64(num.parse().with_context(invalid)?),
+        "" if num.contains('.') => Variable::F64(num.parse().with_context(invalid)?),
+        "" => {
+            if let Ok(num) = num.parse::<i64>() {
+                Variable::I64(num)
+            } else if let Ok(num) = num.parse::<i128>() {
+                Variable::I128(num)
+            } else {
+                Variable::U128(num.parse().with_context(invalid)?)
+            }
+        }
+        _ => bail!("Invalid number type {}", suffix)
+    })
+}
+
+fn tokenize(expr: &str) -> Result<Vec<MathToken>> {
+    let chars: Vec<char> = expr.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let letter = chars[i];
+        match letter {
+            ' ' | '\t' | '\n' => {},
+            '+' => tokens.push(MathToken::Plus),
+            '-' => tokens.push(MathToken::Minus),
+            '*' => if chars.get(i + 1) == Some(&'*') {
+                tokens.push(MathToken::Power);
+                i += 1;
+            } else {
+                tokens.push(MathToken::Multiply);
+            },
+            '/' => tokens.push(MathToken::Divide),
+            '%' => tokens.push(MathToken::Remainder),
+            '(' => tokens.push(MathToken::ParenthesisStart),
+            ')' => tokens.push(MathToken::ParenthesisEnd),
+            '0'..='9' | '.' => {
+                let start = i;
+                while i + 1 < chars.len() && (chars[i + 1].is_ascii_alphanumeric() || chars[i + 1] == '.') {
+                    i += 1;
+                }
+                let str: String = chars[start..=i].iter().collect();
+                tokens.push(MathToken::Number(parse_number(&str)?));
+            },
+            'a'..='z' | 'A'..='Z' | '_' | '$' => {
+                let start = if letter == '$' { i + 1 } else { i };
+                while i + 1 < chars.len() && (chars[i + 1].is_ascii_alphanumeric() || matches!(chars[i + 1], '_' | ':' | '?')) {
+                    i += 1;
+                }
+                let str: String = chars[start..=i].iter().collect();
+                if str.is_empty() { bail!("Expected variable name after $") }
+                tokens.push(MathToken::Variable(str));
+            },
+            letter => bail!("Unexpected character '{}' in math expression", letter)
+        }
+        i += 1;
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser evaluating the expression as it goes
+struct MathParser<'a> {
+    tokens: Vec<MathToken>,
+    i: usize,
+    ctx: &'a mut Context
+}
+
+impl MathParser<'_> {
+    fn peek(&self) -> Option<&MathToken> {
+        self.tokens.get(self.i)
+    }
+
+    fn next(&mut self) -> Option<MathToken> {
+        let token = self.tokens.get(self.i).cloned();
+        self.i += 1;
+        token
+    }
+
+    /// sum = product (('+' | '-') product)*
+    fn parse_sum(&mut self) -> Result<Variable> {
+        let mut left = self.parse_product()?;
+        while let Some(token) = self.peek() {
+            let token = token.clone();
+            if !matches!(token, MathToken::Plus | MathToken::Minus) { break }
+            self.i += 1;
+            let right = self.parse_product()?;
+            left = apply(&token, &left, &right)?;
+        }
+        Ok(left)
+    }
+
+    /// product = unary (('*' | '/' | '%') unary)*
+    fn parse_product(&mut self) -> Result<Variable> {
+        let mut left = self.parse_unary()?;
+        while let Some(token) = self.peek() {
+            let token = token.clone();
+            if !matches!(token, MathToken::Multiply | MathToken::Divide | MathToken::Remainder) { break }
+            self.i += 1;
+            let right = self.parse_unary()?;
+            left = apply(&token, &left, &right)?;
+        }
+        Ok(left)
+    }
+
+    /// unary = ('-' | '+') unary | power
+    fn parse_unary(&mut self) -> Result<Variable> {
+        match self.peek() {
+            Some(MathToken::Minus) => {
+                self.i += 1;
+                let val = self.parse_unary()?;
+                // negating an unsigned integer promotes it to a signed one
+                let kind = NumberKind::of(&val)?;
+                let zero = if kind.is_float() { convert(&Variable::I32(0), kind)? } else { Variable::I32(0) };
+                apply(&MathToken::Minus, &zero, &val)
+            },
+            Some(MathToken::Plus) => {
+                self.i += 1;
+                self.parse_unary()
+            },
+            _ => self.parse_power()
+        }
+    }
+
+    /// power = primary ('**' unary)?, right associative
+    fn parse_power(&mut self) -> Result<Variable> {
+        let base = self.parse_primary()?;
+        if matches!(self.peek(), Some(MathToken::Power)) {
+            self.i += 1;
+            let exponent = self.parse_unary()?;
+            return apply(&MathToken::Power, &base, &exponent);
+        }
+        Ok(base)
+    }
+
+    /// primary = number | variable | '(' sum ')'
+    fn parse_primary(&mut self) -> Result<Variable> {
+        match self.next() {
+            Some(MathToken::Number(num)) => Ok(num),
+            Some(MathToken::Variable(name)) => {
+                let val = self.ctx.get_var(&name).with_context(|| format!("Variable {} not set", name))?.clone();
+                match val {
+                    Variable::String(str) => parse_number(str.trim()).with_context(|| format!("Variable {} is not a number", name)),
+                    Variable::Bool(_) | Variable::Array(_) | Variable::HMap(_) => bail!("Variable {} is not a number", name),
+                    val => Ok(val)
+                }
+            },
+            Some(MathToken::ParenthesisStart) => {
+                let val = self.parse_sum()?;
+                match self.next() {
+                    Some(MathToken::ParenthesisEnd) => Ok(val),
+                    _ => bail!("Expected closing parenthesis in math expression")
+                }
+            },
+            Some(token) => bail!("Unexpected {} in math expression", token.to_str()),
+            None => bail!("Unexpected end of math expression")
+        }
+    }
+}
+
+/// Applies a binary operator, promoting both operands to a common type
+fn apply(operator: &MathToken, left: &Variable, right: &Variable) -> Result<Variable> {
+    let kind = NumberKind::promote(NumberKind::of(left)?, NumberKind::of(right)?);
+    if kind.is_float() {
+        let (a, b) = (to_f64(left)?, to_f64(right)?);
+        let res = match operator {
+            MathToken::Plus => a + b,
+            MathToken::Minus => a - b,
+            MathToken::Multiply => a * b,
+            MathToken::Divide | MathToken::Remainder if b == 0.0 => bail!("Division by zero"),
+            MathToken::Divide => a / b,
+            MathToken::Remainder => a % b,
+            MathToken::Power => a.powf(b),
+            _ => bail!("Invalid operator {}", operator.to_str())
+        };
+        return convert(&Variable::F64(res), kind);
+    }
+    if matches!(operator, MathToken::Divide | MathToken::Remainder) && to_i128(right).ok() == Some(0) {
+        bail!("Division by zero");
+    }
+    let res = if kind.is_unsigned() {
+        let (a, b) = (to_u128(left)?, to_u128(right)?);
+        let res = match operator {
+            MathToken::Plus => a.checked_add(b),
+            MathToken::Minus => a.checked_sub(b),
+            MathToken::Multiply => a.checked_mul(b),
+            MathToken::Divide => a.checked_div(b),
+            MathToken::Remainder => a.checked_rem(b),
+            MathToken::Power => a.checked_pow(u32::try_from(b).with_context(|| "Exponent too large")?),
+            _ => bail!("Invalid operator {}", operator.to_str())
+        };
+        Variable::U128(res.with_context(|| format!("Arithmetic overflow in {} {} {}", a, operator.to_str(), b))?)
+    } else {
+        let (a, b) = (to_i128(left)?, to_i128(right)?);
+        let res = match operator {
+            MathToken::Plus => a.checked_add(b),
+            MathToken::Minus => a.checked_sub(b),
+            MathToken::Multiply => a.checked_mul(b),
+            MathToken::Divide => a.checked_div(b),
+            MathToken::Remainder => a.checked_rem(b),
+            MathToken::Power => {
+                if b < 0 { bail!("Negative exponent {} for integer power, use a float base instead", b) }
+                a.checked_pow(u32::try_from(b).with_context(|| "Exponent too large")?)
+            },
+            _ => bail!("Invalid operator {}", operator.to_str())
+        };
+        Variable::I128(res.with_context(|| format!("Arithmetic overflow in {} {} {}", a, operator.to_str(), b))?)
+    };
+    convert(&res, kind).with_context(|| format!("Arithmetic overflow, result {} doesn't fit into {}", res, format!("{:?}", kind).to_lowercase()))
+}
+
+/// Reads a value as a number, parsing it if it's a string
+pub fn to_number(var: &Variable) -> Result<Variable> {
+    match var {
+        Variable::String(str) => parse_number(str.trim()),
+        var => {
+            NumberKind::of(var)?;
+            Ok(var.clone())
+        }
+    }
+}
+
+/// Converts a value to the numeric type with the given name (like i64 or f32). Returns None if the name isn't a
+/// numeric type
+pub fn cast(var: &Variable, name: &str) -> Result<Option<Variable>> {
+    match NumberKind::from_name(name) {
+        Some(kind) => Ok(Some(convert(&to_number(var)?, kind)?)),
+        None => Ok(None)
+    }
+}
+
+/// Compares two numbers after promoting them to a common type
+pub fn compare(left: &Variable, right: &Variable) -> Result<Ordering> {
+    let (left, right) = (to_number(left)?, to_number(right)?);
+    let kind = NumberKind::promote(NumberKind::of(&left)?, NumberKind::of(&right)?);
+    Ok(if kind.is_float() {
+        to_f64(&left)?.partial_cmp(&to_f64(&right)?).with_context(|| "Cannot compare NaN")?
+    } else if kind.is_unsigned() {
+        to_u128(&left)?.cmp(&to_u128(&right)?)
+    } else {
+        to_i128(&left)?.cmp(&to_i128(&right)?)
+    })
+}
+
+/// Evaluates a math expression, like the contents of $((...))
+pub fn eval(expr: &str, ctx: &mut Context) -> Result<Variable> {
+    let tokens = tokenize(expr)?;
+    let mut parser = MathParser { tokens, i: 0, ctx };
+    let res = parser.parse_sum()?;
+    if let Some(token) = parser.peek() {
+        bail!("Unexpected {} in math expression", token.to_str());
+    }
+    Ok(res)
+}
@@ -2,9 +2,11 @@ pub mod vars;
 pub mod ast;
 pub mod tokens;
 mod exec;
+pub mod glob;
+pub mod math;
 
 use crate::parser::ast::{build_tree};
-use crate::parser::exec::exec_tree;
+use crate::parser::exec::{capture_command, exec_tree};
 use crate::parser::tokens::{tokenize};
 use anyhow::Result;
 
@@ -17,6 +19,17 @@ pub fn exec(reader: &mut dyn std::io::BufRead, ctx: &mut vars::Context) -> Resul
     Ok(())
 }
 
+/// Runs a function or command and returns its output, like $(name args). $? isn't changed by it
+pub fn capture(name: &str, args: Vec<vars::Variable>, ctx: &mut vars::Context) -> Result<String> {
+    let code = ctx.get_var("?").cloned();
+    let output = capture_command(ctx, name, args);
+    if let Some(code) = code {
+        ctx.set_var(String::from("?"), code);
+    }
+    output
+}
+
+#[allow(dead_code)]
 pub fn escape(str: String) -> String {
     str
 }
@@ -4,19 +4,27 @@ use anyhow::{Result, bail};
 pub struct Token {
     pub token: Tokens,
     pub start: usize,
+    #[allow(dead_code)]
     pub end: usize
 }
 
 #[derive(Debug)]
 pub enum Tokens {
     Space,
-    Literal(String),
+    /// a word or the part of it between quotes, quoted (true) parts aren't glob expanded
+    Literal(String, bool),
     Let,
     ExportSet,
     StringVariable(String, bool),
+    /// contents of ${...} with an operator, like name:-default or #name
+    ParameterExpansion(String),
+    /// ~ or ~user at the start of a word
+    Tilde(String),
     ArrayVariable(String, bool),
     ArrayFunction(String),
     StringFunction(String),
+    /// contents of $((...))
+    Math(String),
     ParenthesisStart,
     ParenthesisEnd,
     ArrayStart,
@@ -29,12 +37,27 @@ pub enum Tokens {
     Function,
     End,
     SubStart,
+    /// @( captures output split into an array
+    ArraySubStart,
+    /// <( or >( (write is true)
+    ProcessSubStart { write: bool },
     RedirectInto,
     FileRead,
-    FileWrite,
+    /// <<EOF here-document, with the body filled in once the end of the line is reached
+    HereDoc { content: String, expand: bool },
+    /// <<< here-string, followed by its value
+    HereString,
+    /// > (fd 1), 2> or >> to append
+    FileWrite { fd: i32, append: bool },
+    /// &> or &>>, writes both stdout and stderr to a file
+    FileWriteAll { append: bool },
+    /// 2>&1 duplicates fd 1 into fd 2, >&- closes fd 1
+    FileDuplicate { fd: i32, target: Option<i32> },
     And,
     Or,
     Break,
+    Continue,
+    Return,
     JobCommandEnd
 }
 
@@ -53,27 +76,38 @@ impl Tokens {
             ")" => Tokens::ParenthesisEnd,
             "[" => Tokens::ArrayStart,
             "]" => Tokens::ArrayEnd,
-            ">" => Tokens::FileWrite,
-            "<" => Tokens::FileRead,
             "|" => Tokens::RedirectInto,
             "\r\n" | "\n" | ";" => Tokens::CommandEnd(str.chars().next().unwrap()),
             "&&" => Tokens::And,
             "||" => Tokens::Or,
             "=" => Tokens::ExportSet,
             "break" => Tokens::Break,
-            _ => Tokens::Literal(str)
+            "continue" => Tokens::Continue,
+            "return" => Tokens::Return,
+            "function" => Tokens::Function,
+            _ => Tokens::Literal(str, false)
         }
     }
 
+    /// Whether a command can follow the token
+    pub fn starts_command(&self) -> bool {
+        matches!(self, Tokens::CommandEnd(_) | Tokens::RedirectInto | Tokens::And | Tokens::Or | Tokens::JobCommandEnd
+            | Tokens::SubStart | Tokens::ArraySubStart | Tokens::ProcessSubStart { .. } | Tokens::ParenthesisStart | Tokens::If
+            | Tokens::While | Tokens::Else)
+    }
+
     pub(crate) fn to_str(&self) -> String {
         match self {
             Tokens::Space => " ".to_string(),
-            Tokens::Literal(str) => str.clone(),
+            Tokens::Literal(str, _) => str.clone(),
             Tokens::Let => "let".to_string(),
             Tokens::StringVariable(str, bool) => format!("${}{}{}", match bool { true => "{", false => ""}, str.as_str(), match bool { true => "{", false => "" }),
             Tokens::ArrayVariable(str, bool) => format!("@{}{}{}", match bool { true => "{", false => ""}, str.as_str(), match bool { true => "{", false => "" }),
             Tokens::ArrayFunction(str) => format!("@{}", str.as_str()),
             Tokens::StringFunction(str) => format!("${}", str.as_str()),
+            Tokens::Math(str) => format!("$(({}))", str),
+            Tokens::ParameterExpansion(str) => format!("${{{}}}", str),
+            Tokens::Tilde(user) => format!("~{}", user),
             Tokens::CommandEnd(str) => str.to_string(),
             Tokens::ExportSet => "=".to_string(),
             Tokens::Function => "function".to_string(),
@@ -83,16 +117,27 @@ impl Tokens {
             Tokens::For => "for".to_string(),
             Tokens::End => "end".to_string(),
             Tokens::SubStart => "$(".to_string(),
+            Tokens::ArraySubStart => "@(".to_string(),
+            Tokens::ProcessSubStart { write } => if *write { ">(".to_string() } else { "<(".to_string() },
             Tokens::ParenthesisStart => "(".to_string(),
             Tokens::ParenthesisEnd => ")".to_string(),
             Tokens::ArrayStart => "[".to_string(),
             Tokens::ArrayEnd => "]".to_string(),
             Tokens::RedirectInto => "|".to_string(),
             Tokens::FileRead => "<".to_string(),
-            Tokens::FileWrite => ">".to_string(),
+            Tokens::HereDoc { .. } => "<<".to_string(),
+            Tokens::HereString => "<<<".to_string(),
+            Tokens::FileWrite { fd, append } => format!("{}>{}", if *fd == 1 { String::new() } else { fd.to_string() }, if *append { ">" } else { "" }),
+            Tokens::FileWriteAll { append } => format!("&>{}", if *append { ">" } else { "" }),
+            Tokens::FileDuplicate { fd, target } => format!("{}>&{}", if *fd == 1 { String::new() } else { fd.to_string() }, match target {
+                Some(target) => target.to_string(),
+                None => String::from("-")
+            }),
             Tokens::And => "&&".to_string(),
             Tokens::Or => "||".to_string(),
             Tokens::Break => "break".to_string(),
+            Tokens::Continue => "continue".to_string(),
+            Tokens::Return => "return".to_string(),
             Tokens::JobCommandEnd => "&".to_string()
         }
     }
@@ -102,12 +147,20 @@ impl Tokens {
 fn read_var_ahead(i: usize, text: &str) -> Result<(usize, Token)> {
     let mut x = i;
     let mut buf = String::new();
-    let parens_mode = text.chars().nth(x + 1).unwrap() == '{';
+    let parens_mode = text.chars().nth(x + 1) == Some('{');
     if parens_mode { x += 1 }
     loop {
         x += 1;
-        let letter: char = text.chars().nth(x).unwrap();
+        // a variable can end the input, ${ has to be closed
+        let letter: char = match text.chars().nth(x) {
+            Some(letter) => letter,
+            None if parens_mode => bail!("Unterminated variable ${{{}", buf),
+            None => break
+        };
         match letter {
+            '#' if parens_mode && buf.is_empty() => return read_parameter_ahead(i, text),
+            ':' if parens_mode && matches!(text.chars().nth(x + 1), Some('-' | '=' | '?')) => return read_parameter_ahead(i, text),
+            '#' | '%' | '/' | '^' | ',' if parens_mode && !buf.is_empty() => return read_parameter_ahead(i, text),
             'a'..='z' | 'A'..='Z' | '0'..='9' | ':' | '_' => {
                 buf.push(letter);
             }
@@ -117,7 +170,7 @@ fn read_var_ahead(i: usize, text: &str) -> Result<(usize, Token)> {
                 }
                 break;
             }
-            '?' => {
+            '?' | '!' => {
                 buf.push(letter);
                 x += 1;
                 break;
@@ -133,21 +186,176 @@ fn read_var_ahead(i: usize, text: &str) -> Result<(usize, Token)> {
     Ok((x - i - 1, token))
 }
 
+/// Reads ${...} with an operator, up to the matching closing brace
+fn read_parameter_ahead(i: usize, text: &str) -> Result<(usize, Token)> {
+    let mut content = String::new();
+    let mut lvl = 0;
+    let mut chars = text.chars().skip(i + 2);
+    loop {
+        let letter = match chars.next() {
+            Some(letter) => letter,
+            None => bail!("Unterminated parameter expansion ${{{}", content)
+        };
+        match letter {
+            '\\' => {
+                content.push(letter);
+                content.extend(chars.next());
+                continue;
+            },
+            '{' => lvl += 1,
+            '}' if lvl == 0 => break,
+            '}' => lvl -= 1,
+            _ => {}
+        }
+        content.push(letter);
+    }
+    let len = content.chars().count() + 2;
+    Ok((len, Token { token: Tokens::ParameterExpansion(content), start: i, end: i + len }))
+}
+
+/// Reads a math expression in $((...)), up to the matching closing parenthesis
+fn read_math_ahead(i: usize, text: &str) -> Result<(usize, Token)> {
+    let mut expr = String::new();
+    let mut lvl = 0;
+    let mut chars = text.chars().skip(i + 3).peekable();
+    loop {
+        let letter = match chars.next() {
+            Some(letter) => letter,
+            None => bail!("Unterminated math expression")
+        };
+        match letter {
+            '(' => lvl += 1,
+            ')' if lvl == 0 => {
+                if chars.peek() != Some(&')') { bail!("Expected )) to end math expression") }
+                break;
+            },
+            ')' => lvl -= 1,
+            _ => {}
+        }
+        expr.push(letter);
+    }
+    let len = expr.chars().count() + 4;
+    Ok((len, Token { token: Tokens::Math(expr), start: i, end: i + len }))
+}
+
+/// Reads an output redirection (> >> &> &>> >&2 >&-) starting at i. fd is the descriptor written before it, like 2 in 2>
+fn read_redirect_ahead(i: usize, text: &str, fd: i32) -> Result<(usize, Token)> {
+    let at = |x: usize| text.chars().nth(x);
+    let mut x = i;
+    let all = at(x) == Some('&');
+    if all { x += 1 }
+    x += 1;
+    let append = at(x) == Some('>');
+    if append { x += 1 }
+    let token = if !all && !append && at(x) == Some('&') {
+        x += 1;
+        let mut target = String::new();
+        while let Some(letter) = at(x).filter(|letter| letter.is_ascii_digit()) {
+            target.push(letter);
+            x += 1;
+        }
+        if !target.is_empty() {
+            Tokens::FileDuplicate { fd, target: Some(target.parse()?) }
+        } else if at(x) == Some('-') {
+            x += 1;
+            Tokens::FileDuplicate { fd, target: None }
+        } else {
+            // >&file is the same as &>file
+            Tokens::FileWriteAll { append: false }
+        }
+    } else if all {
+        Tokens::FileWriteAll { append }
+    } else {
+        Tokens::FileWrite { fd, append }
+    };
+    Ok((x - i - 1, Token { token, start: i, end: x - 1 }))
+}
+
+/// A here-document waiting for the end of its line, after which its body starts
+struct PendingHereDoc {
+    token: usize,
+    delimiter: String,
+    strip_tabs: bool
+}
+
+/// Reads the delimiter of a here-document starting at i (the first <). Quoting the delimiter disables expansion
+fn read_heredoc_ahead(i: usize, text: &str) -> Result<(usize, String, bool, bool)> {
+    let at = |x: usize| text.chars().nth(x);
+    let mut x = i + 2;
+    let strip_tabs = at(x) == Some('-');
+    if strip_tabs { x += 1 }
+    while at(x) == Some(' ') { x += 1 }
+    let mut delimiter = String::new();
+    let mut quoted = false;
+    while let Some(letter) = at(x) {
+        match letter {
+            '\'' | '"' | '\\' => quoted = true,
+            ' ' | '\t' | '\n' | '\r' | ';' | '|' | '&' | '<' | '>' | '(' | ')' => break,
+            letter => delimiter.push(letter)
+        }
+        x += 1;
+    }
+    if delimiter.is_empty() { bail!("Expected here-document delimiter after <<") }
+    Ok((x - i - 1, delimiter, !quoted, strip_tabs))
+}
+
+/// Reads the bodies of the pending here-documents, starting after the newline at i. Returns the number of characters
+/// they take up
+fn read_heredoc_bodies(i: usize, text: &str, tokens: &mut [Token], heredocs: Vec<PendingHereDoc>) -> Result<usize> {
+    let rest: String = text.chars().skip(i + 1).collect();
+    let mut lines = rest.split_inclusive('\n');
+    let mut len = 0;
+    for heredoc in heredocs {
+        let mut content = String::new();
+        loop {
+            let line = match lines.next() {
+                Some(line) => line,
+                None => bail!("Here-document not ended, expected {}", heredoc.delimiter)
+            };
+            len += line.chars().count();
+            let line = if heredoc.strip_tabs { line.trim_start_matches('\t') } else { line };
+            if line.trim_end_matches(['\n', '\r']) == heredoc.delimiter { break }
+            content.push_str(line);
+        }
+        if let Tokens::HereDoc { content: body, .. } = &mut tokens[heredoc.token].token {
+            *body = content;
+        }
+    }
+    Ok(len)
+}
+
+/// Whether the next letter starts a new word
+fn at_word_start(buf: &str, tokens: &[Token]) -> bool {
+    buf.is_empty() && matches!(tokens.last().map(|token| &token.token), None | Some(Tokens::Space | Tokens::CommandEnd(_) | Tokens::ExportSet
+        | Tokens::SubStart | Tokens::ArraySubStart | Tokens::ParenthesisStart | Tokens::ArrayStart))
+}
+
+#[allow(clippy::collapsible_match)]
 pub fn tokenize(reader: &mut dyn std::io::BufRead) -> Result<Vec<Token>> {
     let mut quote_active = false;
     let mut double_quote_active = false;
     let mut escape_active = false;
     let mut text = String::new();
     reader.read_to_string(&mut text)?;
-    let text_length = text.len();
+    let text_length = text.chars().count();
 
     let mut tokens: Vec<Token> = Vec::new();
 
-    fn save_buf(buf: &mut String, tokens: &mut Vec<Token>, i: usize) {
-        if !buf.is_empty() { tokens.push(Token { token: Tokens::detect(std::mem::take(buf)), end: i, start: i - buf.len() }) }
+    /// Quoted words are always literals, so "<" or "if" can be passed as arguments
+    fn save_buf(buf: &mut String, quoted: &mut bool, tokens: &mut Vec<Token>, i: usize) {
+        if !buf.is_empty() {
+            let start = i - buf.chars().count();
+            let token = if *quoted { Tokens::Literal(std::mem::take(buf), true) } else { Tokens::detect(std::mem::take(buf)) };
+            tokens.push(Token { token, end: i, start });
+        }
+        *quoted = false;
     }
 
     let mut buf = String::new();
+    let mut quoted = false;
+    let mut heredocs: Vec<PendingHereDoc> = Vec::new();
+    // parentheses opened in each $(...) started between double quotes, the quotes continue after its )
+    let mut quoted_subs: Vec<usize> = Vec::new();
     let mut skipper = 0;
     for i in 0..text_length {
         if skipper > 0 {
@@ -155,27 +363,43 @@ pub fn tokenize(reader: &mut dyn std::io::BufRead) -> Result<Vec<Token>> {
             continue;
         }
         let letter: &char = &text.chars().nth(i).unwrap();
+        let escaped = escape_active;
         let mut buf_add = true;
         match letter {
             '"' => if !escape_active && !quote_active { double_quote_active = !double_quote_active; buf_add = false },
             '\'' => if !escape_active && !double_quote_active { quote_active = !quote_active; buf_add = false },
+            // @( only captures an array as a word of its own, in a word or between double quotes it's just text
+            '@' if text.chars().nth(i + 1) == Some('(') && (double_quote_active || !at_word_start(&buf, &tokens)) => {},
             '$' | '@' => if !escape_active && !quote_active {
-                save_buf(&mut buf, &mut tokens, i);
-                if *letter == '$' && text_length > i && text.chars().nth(i + 1).unwrap() == '(' {
-                    tokens.push(Token { token: Tokens::SubStart, start: i, end: i+1 });
+                save_buf(&mut buf, &mut quoted, &mut tokens, i);
+                if *letter == '$' && text.chars().skip(i + 1).take(2).eq("((".chars()) {
+                    let (skippers, token) = read_math_ahead(i, &text)?;
+                    tokens.push(token);
+                    skipper = skippers;
+                    buf_add = false;
+                } else if text.chars().nth(i + 1) == Some('(') {
+                    let token = if *letter == '$' { Tokens::SubStart } else { Tokens::ArraySubStart };
+                    tokens.push(Token { token, start: i, end: i+1 });
+                    if double_quote_active {
+                        quoted_subs.push(0);
+                        double_quote_active = false;
+                    } else if let Some(open) = quoted_subs.last_mut() {
+                        *open += 1;
+                    }
                     skipper = 1;
                     buf_add = false;
                 } else {
                     let (mut skippers, mut token) = read_var_ahead(i, &text)?;
                     match token.token {
-                        Tokens::StringVariable(ref str, bool) => if !bool && !double_quote_active && text.len() > i + skippers + 1 && text.chars().nth(i + skippers + 1).unwrap() == '(' {
+                        Tokens::StringVariable(ref str, bool) => if !bool && !double_quote_active && text_length > i + skippers + 1 && text.chars().nth(i + skippers + 1).unwrap() == '(' {
                             skippers += 1;
                             token = Token { token: Tokens::StringFunction(str.clone()), end: i + skippers, start: i };
                         },
-                        Tokens::ArrayVariable(ref str, bool) => if !bool && !double_quote_active && text.len() > i + skippers + 1 && text.chars().nth(i + skippers + 1).unwrap() == '(' {
+                        Tokens::ArrayVariable(ref str, bool) => if !bool && !double_quote_active && text_length > i + skippers + 1 && text.chars().nth(i + skippers + 1).unwrap() == '(' {
                             skippers += 1;
                             token = Token { token: Tokens::ArrayFunction(str.clone()), end: i+skippers, start: i };
                         }
+                        Tokens::ParameterExpansion(_) => {},
                         _ => bail!("Cannot happen")
                     }
                     tokens.push(token);
@@ -184,20 +408,27 @@ pub fn tokenize(reader: &mut dyn std::io::BufRead) -> Result<Vec<Token>> {
                 }
             },
             ';' | '\r' | '\n' => if !escape_active && !quote_active && !double_quote_active {
-                save_buf(&mut buf, &mut tokens, i);
+                save_buf(&mut buf, &mut quoted, &mut tokens, i);
                 tokens.push(Token { token: Tokens::CommandEnd(*letter), start: i, end: i });
                 let mut x = 0;
-                while x < text.len() - 1 && matches!(text.chars().nth(x).unwrap(), '\n' | '\r' | ';' | ' ') {
+                while x < text_length - 1 && matches!(text.chars().nth(x).unwrap(), '\n' | '\r' | ';' | ' ') {
                     x += 1;
                 }
                 if x > 0 {
                     skipper = x - 1;
                 }
+                if *letter == '\n' && !heredocs.is_empty() {
+                    skipper = read_heredoc_bodies(i, &text, &mut tokens, std::mem::take(&mut heredocs))?;
+                }
                 buf_add = false;
             },
             '&' => if !escape_active && !quote_active && !double_quote_active {
-                save_buf(&mut buf, &mut tokens, i);
-                if i + 1 < text.len() && text.chars().nth(i+1).unwrap() == '&' {
+                save_buf(&mut buf, &mut quoted, &mut tokens, i);
+                if text.chars().nth(i + 1) == Some('>') {
+                    let (skippers, token) = read_redirect_ahead(i, &text, 1)?;
+                    tokens.push(token);
+                    skipper = skippers;
+                } else if i + 1 < text_length && text.chars().nth(i+1).unwrap() == '&' {
                     tokens.push(Token { token: Tokens::And, start: i, end: i+1 });
                     skipper = 1;
                 } else {
@@ -206,8 +437,8 @@ pub fn tokenize(reader: &mut dyn std::io::BufRead) -> Result<Vec<Token>> {
                 buf_add = false;
             },
             '|' => if !escape_active && !quote_active && !double_quote_active {
-                save_buf(&mut buf, &mut tokens, i);
-                if i + 1 < text.len() && text.chars().nth(i+1).unwrap() == '|' {
+                save_buf(&mut buf, &mut quoted, &mut tokens, i);
+                if i + 1 < text_length && text.chars().nth(i+1).unwrap() == '|' {
                     tokens.push(Token { token: Tokens::Or, start: i, end: i+1 });
                     skipper = 1;
                 } else {
@@ -215,33 +446,90 @@ pub fn tokenize(reader: &mut dyn std::io::BufRead) -> Result<Vec<Token>> {
                 }
                 buf_add = false;
             },
-            ' ' => if !escape_active && !quote_active && !double_quote_active {
-                save_buf(&mut buf, &mut tokens, i);
+            ' ' | '\t' => if !escape_active && !quote_active && !double_quote_active {
+                save_buf(&mut buf, &mut quoted, &mut tokens, i);
                 tokens.push(Token { token: Tokens::Space, start: i, end: i });
                 let mut x = i;
-                while text.chars().nth(x).unwrap() == ' ' {
+                while matches!(text.chars().nth(x), Some(' ' | '\t')) {
                     x += 1;
                 }
                 skipper = x - i - 1;
                 buf_add = false;
             },
+            // >= and <= are comparison operators for test
+            '>' => if !escape_active && !quote_active && !double_quote_active && text.chars().nth(i + 1) != Some('=') {
+                if text.chars().nth(i + 1) == Some('(') {
+                    save_buf(&mut buf, &mut quoted, &mut tokens, i);
+                    tokens.push(Token { token: Tokens::ProcessSubStart { write: true }, start: i, end: i + 1 });
+                    if let Some(open) = quoted_subs.last_mut() { *open += 1 }
+                    skipper = 1;
+                } else {
+                    let fd = if !quoted && !buf.is_empty() && buf.chars().all(|letter| letter.is_ascii_digit()) {
+                        std::mem::take(&mut buf).parse()?
+                    } else {
+                        save_buf(&mut buf, &mut quoted, &mut tokens, i);
+                        1
+                    };
+                    let (skippers, token) = read_redirect_ahead(i, &text, fd)?;
+                    tokens.push(token);
+                    skipper = skippers;
+                }
+                buf_add = false;
+            },
+            '<' => if !escape_active && !quote_active && !double_quote_active && text.chars().nth(i + 1) != Some('=') {
+                save_buf(&mut buf, &mut quoted, &mut tokens, i);
+                if text.chars().nth(i + 1) == Some('(') {
+                    tokens.push(Token { token: Tokens::ProcessSubStart { write: false }, start: i, end: i + 1 });
+                    if let Some(open) = quoted_subs.last_mut() { *open += 1 }
+                    skipper = 1;
+                } else if text.chars().skip(i + 1).take(2).eq("<<".chars()) {
+                    tokens.push(Token { token: Tokens::HereString, start: i, end: i + 2 });
+                    skipper = 2;
+                } else if text.chars().nth(i + 1) == Some('<') {
+                    let (skippers, delimiter, expand, strip_tabs) = read_heredoc_ahead(i, &text)?;
+                    heredocs.push(PendingHereDoc { token: tokens.len(), delimiter, strip_tabs });
+                    tokens.push(Token { token: Tokens::HereDoc { content: String::new(), expand }, start: i, end: i + skippers });
+                    skipper = skippers;
+                } else {
+                    tokens.push(Token { token: Tokens::FileRead, start: i, end: i });
+                }
+                buf_add = false;
+            },
+            // only at the start of a word, the user name runs up to the first /
+            '~' => if !escape_active && !quote_active && !double_quote_active && at_word_start(&buf, &tokens) {
+                let user: String = text.chars().skip(i + 1)
+                    .take_while(|letter| letter.is_ascii_alphanumeric() || matches!(letter, '_' | '-' | '.'))
+                    .collect();
+                skipper = user.len();
+                tokens.push(Token { token: Tokens::Tilde(user), start: i, end: i + skipper });
+                buf_add = false;
+            },
             '(' => if !quote_active && !double_quote_active && !escape_active {
-                save_buf(&mut buf, &mut tokens, i);
+                if let Some(open) = quoted_subs.last_mut() { *open += 1 }
+                save_buf(&mut buf, &mut quoted, &mut tokens, i);
                 tokens.push(Token { token: Tokens::ParenthesisStart, start: i, end: i });
                 buf_add = false;
             }
             ')' => if !quote_active && !double_quote_active && !escape_active {
-                save_buf(&mut buf, &mut tokens, i);
+                save_buf(&mut buf, &mut quoted, &mut tokens, i);
                 tokens.push(Token { token: Tokens::ParenthesisEnd, start: i, end: i });
+                match quoted_subs.last_mut() {
+                    Some(0) => {
+                        quoted_subs.pop();
+                        double_quote_active = true;
+                    },
+                    Some(open) => *open -= 1,
+                    None => {}
+                }
                 buf_add = false;
             },
             '[' => if !quote_active && !double_quote_active && !escape_active {
-                save_buf(&mut buf, &mut tokens, i);
+                save_buf(&mut buf, &mut quoted, &mut tokens, i);
                 tokens.push(Token { token: Tokens::ArrayStart, start: i, end: i });
                 buf_add = false;
             },
             ']' => if !quote_active && !double_quote_active && !escape_active {
-                save_buf(&mut buf, &mut tokens, i);
+                save_buf(&mut buf, &mut quoted, &mut tokens, i);
                 tokens.push(Token { token: Tokens::ArrayEnd, start: i, end: i });
                 buf_add = false;
             },
@@ -251,16 +539,19 @@ pub fn tokenize(reader: &mut dyn std::io::BufRead) -> Result<Vec<Token>> {
             } else {
                 escape_active = false;
             },
-            '=' => if !escape_active && !quote_active && !double_quote_active {
-                save_buf(&mut buf, &mut tokens, i);
+            // comparison operators like == != <= >= stay a single literal
+            '=' => if !escape_active && !quote_active && !double_quote_active
+                && !matches!(buf.as_str(), "=" | "!" | "<" | ">")
+                && text.chars().nth(i + 1) != Some('=') {
+                save_buf(&mut buf, &mut quoted, &mut tokens, i);
                 tokens.push(Token { token: Tokens::ExportSet, start: i, end: i });
                 buf_add = false;
             },
             '#' => if !escape_active && !quote_active && !double_quote_active {
-                save_buf(&mut buf, &mut tokens, i);
+                save_buf(&mut buf, &mut quoted, &mut tokens, i);
                 buf_add = false;
                 let mut x = 0;
-                while x + i + 1 < text.len() && text.chars().nth(x + i + 1).unwrap() != '\n' {
+                while x + i + 1 < text_length && text.chars().nth(x + i + 1).unwrap() != '\n' {
                     x += 1;
                 }
                 skipper = x;
@@ -268,11 +559,22 @@ pub fn tokenize(reader: &mut dyn std::io::BufRead) -> Result<Vec<Token>> {
             _ => {}
         }
         if *letter != '\\' { escape_active = false; }
+        // an escaped newline continues the line
+        if escaped && *letter == '\n' { buf_add = false; }
         if buf_add {
+            // quoted and unquoted parts of a word are saved separately, so only the unquoted parts are glob expanded
+            let letter_quoted = escaped || quote_active || double_quote_active;
+            if letter_quoted != quoted && !buf.is_empty() {
+                save_buf(&mut buf, &mut quoted, &mut tokens, i);
+            }
+            quoted = letter_quoted;
             buf.push(*letter);
         }
     }
-    save_buf(&mut buf, &mut tokens, text.len());
+    save_buf(&mut buf, &mut quoted, &mut tokens, text_length);
+    if let Some(heredoc) = heredocs.first() {
+        bail!("Here-document not ended, expected {}", heredoc.delimiter);
+    }
 
     Ok(tokens)
 }
@@ -2,10 +2,14 @@ use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter};
 use std::fs::File;
 use std::io::{Read, Write};
-use std::process::Stdio;
+use std::path::PathBuf;
+use std::process::{Child, Stdio};
+#[allow(unused_imports)]
 use std::sync::Arc;
 use anyhow::{bail, Result};
 use os_pipe::{PipeReader, PipeWriter};
+use crate::completion::CompletionSpec;
+use crate::history::History;
 use crate::parser::ast::FunctionDefinitionExpression;
 
 #[derive(Debug, Clone)]
@@ -25,6 +29,7 @@ pub enum Variable {
 }
 
 impl Display for Variable {
+    #[allow(clippy::get_first)]
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", match self {
             Variable::String(var) => {
@@ -45,8 +50,11 @@ impl Display for Variable {
                     String::from("false")
                 }
             },
-            Variable::HMap(_map) => {
-                String::from("[Object object]")
+            Variable::HMap(map) => {
+                let mut keys: Vec<&String> = map.keys().collect();
+                keys.sort();
+                let entries: Vec<String> = keys.iter().map(|key| format!("{}={}", key, map[*key])).collect();
+                format!("[{}]", entries.join(" "))
             },
             Variable::Array(vars) => {
                 let len = vars.len();
@@ -84,78 +92,87 @@ impl Variable {
     pub fn index(&self, index: &Variable) -> Result<&Variable> {
         match self {
             Variable::HMap(map) => {
-                match index {
-                    Variable::String(key) => {
-                        match map.get(key) {
-                            Some(val) => Ok(val),
-                            None => bail!("Key not found")
-                        }
-                    }
-                    _ => bail!("Cannot index with non-string")
+                let key = map_key(index)?;
+                match map.get(&key) {
+                    Some(val) => Ok(val),
+                    None => bail!("Key {} not found", key)
                 }
             },
             Variable::Array(arr) => {
-                match index {
-                    Variable::I32(idx) => {
-                        match arr.get(*idx as usize) {
-                            Some(val) => Ok(val),
-                            None => bail!("Index out of bounds")
-                        }
-                    }
-                    Variable::I64(idx) => {
-                        match arr.get(*idx as usize) {
-                            Some(val) => Ok(val),
-                            None => bail!("Index out of bounds")
-                        }
-                    }
-                    Variable::I128(idx) => {
-                        match arr.get(*idx as usize) {
-                            Some(val) => Ok(val),
-                            None => bail!("Index out of bounds")
-                        }
-                    }
-                    Variable::F32(idx) => {
-                        match arr.get(*idx as usize) {
-                            Some(val) => Ok(val),
-                            None => bail!("Index out of bounds")
-                        }
-                    }
-                    Variable::F64(idx) => {
-                        match arr.get(*idx as usize) {
-                            Some(val) => Ok(val),
-                            None => bail!("Index out of bounds")
-                        }
-                    }
-                    Variable::U32(idx) => {
-                        match arr.get(*idx as usize) {
-                            Some(val) => Ok(val),
-                            None => bail!("Index out of bounds")
-                        }
-                    }
-                    Variable::U64(idx) => {
-                        match arr.get(*idx as usize) {
-                            Some(val) => Ok(val),
-                            None => bail!("Index out of bounds")
-                        }
-                    }
-                    Variable::U128(idx) => {
-                        match arr.get(*idx as usize) {
-                            Some(val) => Ok(val),
-                            None => bail!("Index out of bounds")
-                        }
-                    }
-                    Variable::String(idx) => {
-                        match arr.get(idx.parse::<usize>()?) {
-                            Some(val) => Ok(val),
-                            None => bail!("Index out of bounds")
-                        }
-                    }
-                    _ => bail!("Cannot index with non-integer")
+                let idx = array_index(index, arr.len())?;
+                match arr.get(idx) {
+                    Some(val) => Ok(val),
+                    None => bail!("Index {} out of bounds", index)
+                }
+            },
+            _ => bail!("Cannot index unsupported types")
+        }
+    }
+
+    /// Mutable variant of index. Missing map keys are inserted as empty maps, so nested assignments create the maps
+    /// on their way
+    pub fn index_mut(&mut self, index: &Variable) -> Result<&mut Variable> {
+        match self {
+            Variable::HMap(map) => Ok(map.entry(map_key(index)?).or_insert_with(|| Variable::HMap(HashMap::new()))),
+            Variable::Array(arr) => {
+                let idx = array_index(index, arr.len())?;
+                match arr.get_mut(idx) {
+                    Some(val) => Ok(val),
+                    None => bail!("Index {} out of bounds", index)
                 }
             },
             _ => bail!("Cannot index unsupported types")
         }
     }
+
+    /// Returns the elements from start up to end. Missing bounds mean the start or end of the array
+    pub fn slice(&self, start: Option<&Variable>, end: Option<&Variable>, inclusive: bool) -> Result<Variable> {
+        let arr = match self {
+            Variable::Array(arr) => arr,
+            _ => bail!("Cannot slice unsupported types")
+        };
+        let start = match start {
+            Some(start) => array_index(start, arr.len())?,
+            None => 0
+        };
+        let end = match end {
+            Some(end) => array_index(end, arr.len())? + if inclusive { 1 } else { 0 },
+            None => arr.len()
+        };
+        if start > end || end > arr.len() {
+            bail!("Slice {}..{} out of bounds", start, end);
+        }
+        Ok(Variable::Array(arr[start..end].to_vec()))
+    }
+}
+
+fn map_key(index: &Variable) -> Result<String> {
+    match index {
+        Variable::HMap(_) | Variable::Array(_) => bail!("Cannot index with {}", index),
+        index => Ok(index.to_string())
+    }
+}
+
+/// Converts an index to a position in an array. Negative indexes count from the end
+fn array_index(index: &Variable, len: usize) -> Result<usize> {
+    let idx: i128 = match index {
+        Variable::I32(idx) => *idx as i128,
+        Variable::I64(idx) => *idx as i128,
+        Variable::I128(idx) => *idx,
+        Variable::U32(idx) => *idx as i128,
+        Variable::U64(idx) => *idx as i128,
+        Variable::U128(idx) => *idx as i128,
+        Variable::F32(idx) => *idx as i128,
+        Variable::F64(idx) => *idx as i128,
+        Variable::String(idx) => match idx.parse() {
+            Ok(idx) => idx,
+            Err(_) => bail!("Cannot index with non-integer {}", idx)
+        },
+        _ => bail!("Cannot index with non-integer")
+    };
+    let idx = if idx < 0 { idx + len as i128 } else { idx };
+    if idx < 0 { bail!("Index {} out of bounds", index) }
+    Ok(idx as usize)
 }
 
 pub struct NativeFunction {
@@ -184,7 +201,9 @@ trait TryClone<T> {
 #[derive(Debug)]
 pub enum WriterOverride {
     Pipe(PipeWriter),
-    File(File)
+    File(File),
+    /// closed with >&-, external commands are started with the descriptor closed
+    Closed
 }
 #[derive(Debug)]
 pub enum ReaderOverride {
@@ -196,7 +215,8 @@ impl TryClone<WriterOverride> for WriterOverride {
     fn try_clone(&self) -> Result<WriterOverride> {
         Ok(match self {
             WriterOverride::Pipe(pipe) => WriterOverride::Pipe(pipe.try_clone()?),
-            WriterOverride::File(file) => WriterOverride::File(file.try_clone()?)
+            WriterOverride::File(file) => WriterOverride::File(file.try_clone()?),
+            WriterOverride::Closed => WriterOverride::Closed
         })
     }
 }
@@ -208,11 +228,28 @@ impl TryClone<ReaderOverride> for ReaderOverride {
         })
     }
 }
-impl From<WriterOverride> for Stdio {
-    fn from(value: WriterOverride) -> Self {
-        match value {
-            WriterOverride::Pipe(pipe) => pipe.into(),
-            WriterOverride::File(file) => file.into()
+impl Write for WriterOverride {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            WriterOverride::Pipe(pipe) => pipe.write(buf),
+            WriterOverride::File(file) => file.write(buf),
+            WriterOverride::Closed => Err(std::io::Error::other("Bad file descriptor"))
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            WriterOverride::Pipe(pipe) => pipe.flush(),
+            WriterOverride::File(file) => file.flush(),
+            WriterOverride::Closed => Ok(())
+        }
+    }
+}
+impl Read for ReaderOverride {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            ReaderOverride::Pipe(pipe) => pipe.read(buf),
+            ReaderOverride::File(file) => file.read(buf)
         }
     }
 }
@@ -225,6 +262,59 @@ impl From<ReaderOverride> for Stdio {
     }
 }
 
+#[derive(Debug)]
+pub struct Job {
+    /// job number, used as %n in job builtins
+    pub id: usize,
+    /// command line the job was started with
+    pub command: String,
+    pub children: Vec<Child>
+}
+
+impl Job {
+    /// Checks whether all processes of the job have exited, returning the exit code of the last one
+    pub fn try_wait(&mut self) -> Result<Option<i32>> {
+        let mut code = Some(0);
+        for child in &mut self.children {
+            code = match child.try_wait()? {
+                Some(status) => code.map(|_| status.code().unwrap_or(-1)),
+                None => None
+            };
+        }
+        Ok(code)
+    }
+
+    /// Waits for all processes of the job, returning the exit code of the last one
+    pub fn wait(&mut self) -> Result<i32> {
+        let mut code = 0;
+        for child in &mut self.children {
+            code = child.wait()?.code().unwrap_or(-1);
+        }
+        Ok(code)
+    }
+
+    /// Describes the job as listed by jobs, with the exit code it finished with
+    pub fn describe(&self, code: Option<i32>) -> String {
+        let status = match code {
+            None => String::from("Running"),
+            Some(0) => String::from("Done"),
+            Some(code) => format!("Exit {}", code)
+        };
+        format!("[{}]  {}\t{}", self.id, status, self.command)
+    }
+
+    /// Sends a signal to all processes of the job
+    pub fn signal(&self, signal: i32) -> Result<()> {
+        for child in &self.children {
+            if unsafe { libc::kill(child.id() as i32, signal) } != 0 {
+                bail!("Failed to signal process {}: {}", child.id(), std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
 pub struct Overrides {
     pub stdin: Option<ReaderOverride>,
     pub stdout: Option<WriterOverride>,
@@ -237,7 +327,7 @@ pub struct Scope {
     pub vars: HashMap<String, Variable>,
     /// list of functions
     pub func: HashMap<String, FunctionDefinitionExpression>,
-    /// list of file descriptors, to be closed when the scope is left
+    /// file descriptors of process substitutions, taken over by the command using them or closed when the scope is left
     pub fd: Vec<usize>,
     pub stdin_override: Option<ReaderOverride>,
     pub stdout_override: Option<WriterOverride>,
@@ -254,7 +344,19 @@ pub struct Context {
     /// number of break statements called
     pub break_num: u16,
     /// number of continue statements called
-    pub continue_num: u16
+    pub continue_num: u16,
+    /// value of the return statement called, set until the function call is left
+    pub return_value: Option<Variable>,
+    /// background jobs started with &
+    pub jobs: Vec<Job>,
+    /// directory stack used by pushd and popd, with the most recently pushed directory last
+    pub dir_stack: Vec<PathBuf>,
+    /// set -o pipefail, pipelines fail if any of their commands fails
+    pub pipefail: bool,
+    /// commands entered in the interactive shell
+    pub history: History,
+    /// completions declared with the complete builtin, by command name
+    pub completions: HashMap<String, Vec<CompletionSpec>>
 }
 
 impl Context {
@@ -264,13 +366,50 @@ impl Context {
             exports: HashMap::new(),
             native_func: HashMap::new(),
             break_num: 0,
-            continue_num: 0
+            continue_num: 0,
+            return_value: None,
+            jobs: Vec::new(),
+            dir_stack: Vec::new(),
+            pipefail: false,
+            history: History::new(),
+            completions: HashMap::new()
         };
         res.add_scope();
         res
     }
+    /// Whether a break, continue or return statement was called and the remaining expressions should be skipped
+    pub fn is_unwinding(&self) -> bool {
+        self.break_num > 0 || self.continue_num > 0 || self.return_value.is_some()
+    }
+
+    /// Removes the jobs that finished, returning their descriptions to tell the user about them
+    pub fn reap_jobs(&mut self) -> Result<Vec<String>> {
+        let mut done = Vec::new();
+        let mut running = Vec::new();
+        for mut job in std::mem::take(&mut self.jobs) {
+            match job.try_wait()? {
+                Some(code) => done.push(job.describe(Some(code))),
+                None => running.push(job)
+            }
+        }
+        self.jobs = running;
+        Ok(done)
+    }
+
     pub fn pop_scope(&mut self) -> Option<Scope> {
-        self.scopes.pop()
+        let mut scope = self.scopes.pop();
+        if let Some(scope) = &mut scope {
+            for fd in scope.fd.drain(..) {
+                unsafe { libc::close(fd as i32); }
+            }
+        }
+        scope
+    }
+    /// Leaves the scopes added after the first len ones, including the ones left behind by an error
+    pub fn restore_scopes(&mut self, len: usize) {
+        while self.scopes.len() > len {
+            self.pop_scope();
+        }
     }
     pub fn add_scope(&mut self) {
         let scope = Scope {
@@ -324,7 +463,7 @@ impl Context {
         vars.insert(key, val);
     }
 
-    pub fn get_func(&mut self, key: &str) -> Option<AnyFunction> {
+    pub fn get_func(&mut self, key: &str) -> Option<AnyFunction<'_>> {
         for scope in self.scopes.iter_mut().rev() {
             let funcs = &mut scope.func;
             let val = funcs.get_mut(key);
@@ -350,7 +489,31 @@ impl Context {
         func.insert(key, val);
     }
 
+    /// Adds a background job, returning its job number
+    pub fn add_job(&mut self, command: String, children: Vec<Child>) -> usize {
+        let id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        self.jobs.push(Job { id, command, children });
+        id
+    }
+
+    /// Gets the current stdout, for native functions to write their output to
+    pub fn get_stdout(&self) -> Result<Box<dyn Write>> {
+        Ok(match self.get_overrides()?.stdout {
+            Some(stdout) => Box::new(stdout),
+            None => Box::new(std::io::stdout())
+        })
+    }
+
+    /// Gets the current stderr, for native functions to write their errors to
+    pub fn get_stderr(&self) -> Result<Box<dyn Write>> {
+        Ok(match self.get_overrides()?.stderr {
+            Some(stderr) => Box::new(stderr),
+            None => Box::new(std::io::stderr())
+        })
+    }
+
     /// Gets relevant overrides. Should only be used before running a command, as it will clone all pipes
+    #[allow(clippy::single_match)]
     pub fn get_overrides(&self) -> Result<Overrides> {
         let mut overrides = Overrides {
             stdin: None,
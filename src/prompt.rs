@@ -0,0 +1,187 @@
+use std::ffi::CStr;
+use anyhow::Result;
+use crate::parser;
+use crate::parser::vars::{AnyFunction, Context};
+
+/// Left prompt when neither PS1 nor rush_prompt is set
+const DEFAULT: &str = "$: ";
+/// Continuation prompt when PS2 isn't set
+const DEFAULT_CONTINUATION: &str = "> ";
+
+/// Prompts drawn by the line editor, rendered before each command is read
+#[derive(Debug, Clone)]
+pub struct Prompt {
+    pub left: String,
+    /// drawn at the right edge of the first input row, while the input leaves room for it
+    pub right: String,
+    /// starts the lines continuing the command after an escaped newline
+    pub continuation: String
+}
+
+impl Prompt {
+    pub fn new() -> Prompt {
+        Prompt { left: DEFAULT.to_string(), right: String::new(), continuation: DEFAULT_CONTINUATION.to_string() }
+    }
+
+    /// The output of the rush_prompt and rush_right_prompt functions, or PS1, RPS1 and PS2 with their escapes expanded.
+    /// PS1 of the environment isn't used, it's usually written for another shell
+    pub fn render(ctx: &mut Context) -> Result<Prompt> {
+        Ok(Prompt {
+            left: render_part(ctx, Some("rush_prompt"), "PS1")?.unwrap_or_else(|| DEFAULT.to_string()),
+            right: render_part(ctx, Some("rush_right_prompt"), "RPS1")?.unwrap_or_default(),
+            continuation: render_part(ctx, None, "PS2")?.unwrap_or_else(|| DEFAULT_CONTINUATION.to_string())
+        })
+    }
+
+    /// Row and column the input starts at, below the first row of the prompt
+    pub fn end(&self, width: usize) -> (usize, usize) {
+        let (mut row, mut col) = (0, 0);
+        for (i, line) in visible(&self.left).split('\n').enumerate() {
+            if i > 0 {
+                row += 1;
+            }
+            let len = line.chars().count();
+            row += len / width;
+            col = len % width;
+        }
+        (row, col)
+    }
+}
+
+/// The output of the function if it's defined, or the expanded variable
+fn render_part(ctx: &mut Context, function: Option<&str>, var: &str) -> Result<Option<String>> {
+    if let Some(function) = function {
+        if matches!(ctx.get_func(function), Some(AnyFunction::UserDefined(_))) {
+            return Ok(Some(parser::capture(function, Vec::new(), ctx)?));
+        }
+    }
+    match ctx.get_var(var).map(|value| value.to_string()) {
+        Some(value) => Ok(Some(expand(&value, ctx)?)),
+        None => Ok(None)
+    }
+}
+
+/// Width of text on the terminal, without escape sequences
+pub fn width(str: &str) -> usize {
+    visible(str).chars().count()
+}
+
+/// Removes CSI sequences like colours and OSC sequences like window titles
+fn visible(str: &str) -> String {
+    let mut out = String::new();
+    let mut chars = str.chars().peekable();
+    while let Some(letter) = chars.next() {
+        if letter != '\x1b' {
+            out.push(letter);
+            continue;
+        }
+        match chars.next() {
+            Some('[') => {
+                for letter in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&letter) { break }
+                }
+            },
+            // ended by BEL or ESC \
+            Some(']') => while let Some(letter) = chars.next() {
+                if letter == '\x07' { break }
+                if letter == '\x1b' && chars.peek() == Some(&'\\') {
+                    chars.next();
+                    break;
+                }
+            },
+            _ => {}
+        }
+    }
+    out
+}
+
+/// Expands \u (user), \h and \H (host), \w and \W (working directory), \? (exit code), \t (time), \$ (# for root),
+/// \e, \n, \\ and \(name), the output of a function or command. \[ and \] are ignored, as escape sequences are
+/// measured without them. Backslashes escape in rush strings too, so they're written doubled, like '\\w'
+fn expand(ps: &str, ctx: &mut Context) -> Result<String> {
+    let chars: Vec<char> = ps.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] != '\\' || i + 1 == chars.len() {
+            out.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        i += 2;
+        match chars[i - 1] {
+            'u' => out += &get_user(),
+            'h' => out += get_host().split('.').next().unwrap_or_default(),
+            'H' => out += &get_host(),
+            'w' => out += &get_dir(ctx, false),
+            'W' => out += &get_dir(ctx, true),
+            '?' => out += &ctx.get_last_exit_code().unwrap_or(0).to_string(),
+            't' => out += &get_time(),
+            '$' => out.push(if unsafe { libc::geteuid() } == 0 { '#' } else { '$' }),
+            'e' => out.push('\x1b'),
+            'n' => out.push('\n'),
+            '\\' => out.push('\\'),
+            '[' | ']' => {},
+            '(' => {
+                let end = chars[i..].iter().position(|letter| *letter == ')').map(|end| i + end).unwrap_or(chars.len());
+                let name: String = chars[i..end].iter().collect();
+                out += &parser::capture(name.trim(), Vec::new(), ctx)?;
+                i = end + 1;
+            },
+            letter => {
+                out.push('\\');
+                out.push(letter);
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn get_user() -> String {
+    if let Ok(user) = std::env::var("USER") {
+        return user;
+    }
+    let passwd = unsafe { libc::getpwuid(libc::geteuid()) };
+    if passwd.is_null() {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr((*passwd).pw_name) }.to_string_lossy().to_string()
+}
+
+fn get_host() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return String::new();
+    }
+    let end = buf.iter().position(|byte| *byte == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..end]).to_string()
+}
+
+/// The working directory with the home directory as ~, or only its last component
+fn get_dir(ctx: &Context, last: bool) -> String {
+    let dir = match ctx.exports.get("PWD") {
+        Some(pwd) => pwd.to_string(),
+        None => std::env::current_dir().map(|dir| dir.to_string_lossy().to_string()).unwrap_or_default()
+    };
+    let home = ctx.exports.get("HOME").map(|home| home.to_string()).or_else(|| std::env::var("HOME").ok());
+    if let Some(home) = home.filter(|home| !home.is_empty()) {
+        if dir == home {
+            return String::from("~");
+        }
+        if let Some(rest) = dir.strip_prefix(&home).filter(|rest| rest.starts_with('/')) {
+            if !last { return format!("~{}", rest) }
+        }
+    }
+    match dir.rsplit_once('/') {
+        Some((_, name)) if last && !name.is_empty() => name.to_string(),
+        _ => dir
+    }
+}
+
+/// Local time as HH:MM:SS
+fn get_time() -> String {
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&now, &mut tm) };
+    format!("{:02}:{:02}:{:02}", tm.tm_hour, tm.tm_min, tm.tm_sec)
+}